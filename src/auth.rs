@@ -0,0 +1,263 @@
+use crate::util;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// The authentication scheme requested by a registry through the
+/// `WWW-Authenticate` header of a 401 response
+/// https://distribution.github.io/distribution/spec/auth/token/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthChallenge {
+    /// The registry didn't ask for any authentication
+    Anonymous,
+    /// Credentials must be sent with every request
+    Basic { realm: Option<String> },
+    /// A token must be fetched from `realm` and sent with every request
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+/// Value of the `Authorization` header to send along with requests
+#[derive(Clone, Debug)]
+pub enum Authorization {
+    Anonymous,
+    /// Base64 encoded `user:password`
+    Basic(String),
+    Bearer(String),
+}
+
+impl Authorization {
+    pub fn header(&self) -> Option<String> {
+        match self {
+            Self::Anonymous => None,
+            Self::Basic(encoded) => Some(format!("Basic {encoded}")),
+            Self::Bearer(token) => Some(format!("Bearer {token}")),
+        }
+    }
+}
+
+/// Response of the token server
+/// https://distribution.github.io/distribution/spec/auth/token/#token-response-fields
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    token: Option<String>,
+    /// OAuth2 compatible alias for `token` used by some registries
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+    issued_at: Option<String>,
+}
+
+/// An authorization along with when it stops being valid
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub authorization: Authorization,
+    /// `None` for authorizations that never expire
+    expires_at: Option<SystemTime>,
+}
+
+impl Token {
+    /// The spec mandates this lifetime for tokens without `expires_in`
+    const DEFAULT_EXPIRY: Duration = Duration::from_secs(60);
+    /// Refresh slightly early so that a token doesn't expire mid-request
+    const EXPIRY_LEEWAY: Duration = Duration::from_secs(10);
+
+    pub fn new(authorization: Authorization) -> Self {
+        Self {
+            authorization,
+            expires_at: None,
+        }
+    }
+
+    pub fn from_response(response: TokenResponse) -> std::io::Result<Self> {
+        let token = response
+            .token
+            .or(response.access_token)
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "No token in token server response",
+                )
+            })?;
+
+        // Prefer the server's notion of when the token was issued, our clock
+        // only matters for comparing against the expiry
+        let issued_at = response
+            .issued_at
+            .as_deref()
+            .and_then(util::parse_rfc3339)
+            .unwrap_or_else(SystemTime::now);
+
+        let expires_in = response
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(Self::DEFAULT_EXPIRY);
+
+        Ok(Self {
+            authorization: Authorization::Bearer(token),
+            expires_at: Some(issued_at + expires_in),
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => SystemTime::now() + Self::EXPIRY_LEEWAY >= expires_at,
+            None => false,
+        }
+    }
+}
+
+/// Split the comma separated `key=value` parameters of a challenge,
+/// respecting quoted values as they can contain commas themselves
+/// realm="https://auth.docker.io/token",scope="repository:a:pull,push"
+fn parse_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = params.chars().peekable();
+
+    loop {
+        // Skip over the separators between parameters
+        while chars.next_if(|c| *c == ',' || c.is_whitespace()).is_some() {}
+
+        let key: String = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .to_ascii_lowercase();
+
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    _ => value.push(c),
+                }
+            }
+        } else {
+            value = chars
+                .by_ref()
+                .take_while(|c| *c != ',')
+                .collect::<String>()
+                .trim()
+                .to_string();
+        }
+
+        parsed.insert(key, value);
+    }
+
+    parsed
+}
+
+impl AuthChallenge {
+    /// Parse the value of a `WWW-Authenticate` header
+    pub fn parse(header: &str) -> std::io::Result<Self> {
+        let header = header.trim();
+        let (scheme, params) = header.split_once(' ').unwrap_or((header, ""));
+        let mut params = parse_params(params);
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => Ok(Self::Basic {
+                realm: params.remove("realm"),
+            }),
+            "bearer" => Ok(Self::Bearer {
+                realm: params.remove("realm").ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Bearer challenge without realm: {header}"),
+                    )
+                })?,
+                service: params.remove("service"),
+                scope: params.remove("scope"),
+            }),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Unsupported authentication scheme: {header}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_with_quoted_commas() {
+        let params = parse_params(
+            r#"realm="https://auth.docker.io/token", service=registry.docker.io,scope="repository:a/b:pull,push""#,
+        );
+
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:a/b:pull,push");
+    }
+
+    #[test]
+    fn params_with_escapes() {
+        let params = parse_params(r#"Realm="a\"b\\c""#);
+
+        assert_eq!(params["realm"], r#"a"b\c"#);
+    }
+
+    #[test]
+    fn bearer_challenge() {
+        assert_eq!(
+            AuthChallenge::parse(
+                r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:a:pull""#
+            )
+            .unwrap(),
+            AuthChallenge::Bearer {
+                realm: "https://ghcr.io/token".to_string(),
+                service: Some("ghcr.io".to_string()),
+                scope: Some("repository:a:pull".to_string()),
+            }
+        );
+
+        assert_eq!(
+            AuthChallenge::parse(r#"bearer realm="https://r/token""#).unwrap(),
+            AuthChallenge::Bearer {
+                realm: "https://r/token".to_string(),
+                service: None,
+                scope: None,
+            }
+        );
+    }
+
+    #[test]
+    fn bearer_challenge_without_realm() {
+        let err = AuthChallenge::parse(r#"Bearer service="ghcr.io""#).unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn basic_challenge() {
+        assert_eq!(
+            AuthChallenge::parse(r#"Basic realm="Registry Realm""#).unwrap(),
+            AuthChallenge::Basic {
+                realm: Some("Registry Realm".to_string())
+            }
+        );
+        assert_eq!(
+            AuthChallenge::parse("Basic").unwrap(),
+            AuthChallenge::Basic { realm: None }
+        );
+    }
+
+    #[test]
+    fn unsupported_challenge() {
+        let err = AuthChallenge::parse("Negotiate abcdef").unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+}
@@ -3,6 +3,9 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+/// Where cgroup v2 is expected to be mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
 pub struct CGroupConfig {
     // The kernel will transform values like 1k, 1m, 1g, ... to
     // bytes by itself, but we should take in bytes directly
@@ -16,6 +19,19 @@ pub struct CGroup {
 }
 
 impl CGroup {
+    /// The cgroup v2 directory of the calling process, from the unified
+    /// hierarchy's `0::/path` entry in /proc/self/cgroup
+    pub fn current() -> Result<PathBuf, std::io::Error> {
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+
+        let path = cgroups
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| std::io::Error::other("Not running under cgroup v2"))?;
+
+        Ok(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+    }
+
     pub fn new(base_cgroup: &Path, config: CGroupConfig) -> Result<Self, std::io::Error> {
         let mut path = base_cgroup.to_path_buf();
         path.push(format!("dabba-{}", nix::unistd::getpid()));
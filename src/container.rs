@@ -0,0 +1,526 @@
+use crate::{
+    registry::ImageConfigRuntime,
+    root::Root,
+    sandbox::{Sandbox, StdioConfig},
+    slirp::PortMapping,
+    util,
+};
+use nix::errno::Errno;
+use nix::fcntl::{FlockArg, OFlag};
+use nix::sys::signal::Signal;
+use nix::unistd::{ForkResult, Pid};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Sent by the monitor of a detached container once it's running, or
+/// followed by the error if it failed to start
+const STARTED: u8 = 0;
+const FAILED: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    Exited,
+}
+
+/// Persisted in `state.json`, only ever written by the process that owns
+/// the sandbox, dabba itself or the monitor of a detached container
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub id: String,
+    /// Reference of the image, or the path passed to `--rootfs`
+    pub image: String,
+    /// Image config with the overrides from the command line applied
+    pub config: ImageConfigRuntime,
+    /// Variables passed with `-e`, applied over the image's
+    pub env: Vec<String>,
+    pub ports: Vec<PortMapping>,
+    pub status: Status,
+    /// Host PID of the container's init, once it's started
+    pub pid: Option<i32>,
+    /// PID of the process that owns the sandbox
+    pub monitor: i32,
+    /// Seconds since the epoch
+    pub created: u64,
+    pub exit_code: Option<i32>,
+    /// Remove the state once the container exits, `--rm`
+    pub auto_remove: bool,
+    /// Seconds to wait after the stop signal before killing the container
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+}
+
+fn default_stop_timeout() -> u64 {
+    Container::DEFAULT_STOP_TIMEOUT
+}
+
+/// A container and it's state directory under the runtime root
+///
+/// ```text
+/// containers/
+///   <id>/
+///     state.json   `State`
+///     log          Output of a detached container and it's monitor
+///     slirp.sock   API socket of slirp4netns
+/// ```
+pub struct Container {
+    dir: PathBuf,
+    pub state: State,
+    /// Reports back to the CLI that detached us, see `detach`
+    notify: Option<File>,
+}
+
+impl Container {
+    /// Length of the ID as shown to the user, like docker
+    pub const SHORT_ID_LEN: usize = 12;
+
+    /// Same as docker
+    pub const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+    /// See `lock_exit`
+    const EXIT_LOCK: &'static str = "exit.lock";
+
+    fn generate_id() -> Result<String, std::io::Error> {
+        let mut bytes = [0_u8; 32];
+        File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+        Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    /// Record a new container, it isn't started until `start`
+    pub fn create(
+        root: &Root,
+        image: String,
+        config: ImageConfigRuntime,
+        env: Vec<String>,
+        ports: Vec<PortMapping>,
+        auto_remove: bool,
+        stop_timeout: u64,
+    ) -> Result<Self, std::io::Error> {
+        let id = Self::generate_id()?;
+        let dir = root.containers_dir().join(&id);
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+
+        let container = Self {
+            dir,
+            state: State {
+                id,
+                image,
+                config,
+                env,
+                ports,
+                status: Status::Created,
+                pid: None,
+                monitor: std::process::id() as i32,
+                created: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs(),
+                exit_code: None,
+                auto_remove,
+                stop_timeout,
+            },
+            notify: None,
+        };
+
+        container.save()?;
+        File::create(container.dir.join(Self::EXIT_LOCK))?;
+
+        log::info!("Created container {}", container.state.id);
+
+        Ok(container)
+    }
+
+    /// Read the state in `dir`, a container whose owner died without
+    /// recording it's exit is reported as exited
+    fn open(dir: PathBuf) -> Result<Self, std::io::Error> {
+        let mut state: State =
+            util::serde_deserialize_or_err(&std::fs::read_to_string(dir.join("state.json"))?)?;
+
+        // The sandbox dies along with it's owner, see `Sandbox::die_with_parent`
+        if state.status != Status::Exited
+            && nix::sys::signal::kill(Pid::from_raw(state.monitor), None) == Err(Errno::ESRCH)
+        {
+            state.status = Status::Exited;
+        }
+
+        Ok(Self {
+            dir,
+            state,
+            notify: None,
+        })
+    }
+
+    /// Look up a container by it's ID, or a unique prefix of it
+    pub fn load(root: &Root, id: &str) -> Result<Self, std::io::Error> {
+        let not_found = || {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No such container: '{id}'"),
+            )
+        };
+
+        // Don't let the ID escape the containers directory
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(not_found());
+        }
+
+        let dir = root.containers_dir();
+        let mut matches = Vec::new();
+
+        for entry in std::fs::read_dir(&dir).map_err(|_| not_found())? {
+            let name = entry?.file_name();
+
+            if name.to_string_lossy().starts_with(id) {
+                matches.push(name);
+            }
+        }
+
+        match matches.as_slice() {
+            [] => Err(not_found()),
+            [name] => Self::open(dir.join(name)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Multiple containers match '{id}'"),
+            )),
+        }
+    }
+
+    /// All containers, most recently created first
+    pub fn list(root: &Root) -> Result<Vec<Self>, std::io::Error> {
+        let dir = root.containers_dir();
+        let mut containers = Vec::new();
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(containers),
+            Err(err) => return Err(err),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+
+            match Self::open(path.clone()) {
+                Ok(container) => containers.push(container),
+                // Removed meanwhile, or still being created
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => log::warn!("Skipping {path:?}, can't read it's state: {err}"),
+            }
+        }
+
+        containers.sort_by_key(|container| Reverse(container.state.created));
+
+        Ok(containers)
+    }
+
+    /// Re-read the state written by the container's owner
+    pub fn reload(&mut self) -> Result<(), std::io::Error> {
+        self.state = Self::open(self.dir.clone())?.state;
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.status == Status::Running
+    }
+
+    /// Atomically replace `state.json`, readers never see a partial write
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let path = self.dir.join("state.json");
+        let tmp = self
+            .dir
+            .join(format!("state.json.tmp-{}", std::process::id()));
+
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.state)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub fn short_id(&self) -> &str {
+        &self.state.id[..Self::SHORT_ID_LEN]
+    }
+
+    /// Anyone waiting for the container to exit holds this shared, the
+    /// monitor of an `--rm` container takes it exclusively before removing
+    /// it so that they still get to read the exit code
+    fn lock_exit(&self, arg: FlockArg) -> Result<File, std::io::Error> {
+        let file = File::open(self.dir.join(Self::EXIT_LOCK))?;
+        nix::fcntl::flock(file.as_raw_fd(), arg)?;
+
+        Ok(file)
+    }
+
+    /// Keep the container from being removed until the returned file is
+    /// dropped, see `lock_exit`
+    pub fn watch(&self) -> Result<File, std::io::Error> {
+        let removed = || {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Container {} was removed", self.short_id()),
+            )
+        };
+
+        let lock = self.lock_exit(FlockArg::LockShared).map_err(|err| {
+            if err.kind() == std::io::ErrorKind::NotFound {
+                removed()
+            } else {
+                err
+            }
+        })?;
+
+        // We might have been waiting on it's removal
+        if !self.dir.join("state.json").try_exists()? {
+            return Err(removed());
+        }
+
+        Ok(lock)
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        self.dir.join("log")
+    }
+
+    /// Per container, so that the port forwards of concurrently running
+    /// containers don't end up in each other's slirp4netns
+    pub fn slirp_socket(&self) -> PathBuf {
+        self.dir.join("slirp.sock")
+    }
+
+    /// Fork off a monitor process that owns the container from here on, so
+    /// that it keeps running once we exit
+    /// Only returns in the monitor, with it's output going to the container's
+    /// log. We exit once the monitor reports back from `start`, without
+    /// running any destructors, as whatever the container depends on (like
+    /// the image lease) now belongs to the monitor
+    pub fn detach(&mut self) -> Result<(), std::io::Error> {
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(self.log_path())?;
+
+        // Close on exec so that slirp4netns doesn't hold on to it
+        let (read_fd, write_fd) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let (mut read_end, write_end) =
+            unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(write_fd)) };
+
+        std::io::stdout().flush()?;
+
+        match unsafe { nix::unistd::fork()? } {
+            ForkResult::Parent { .. } => {
+                drop(write_end);
+
+                let mut status = [FAILED];
+                let result = match read_end.read(&mut status) {
+                    Ok(1) if status[0] == STARTED => Ok(()),
+                    Ok(1) => {
+                        let mut error = String::new();
+                        read_end.read_to_string(&mut error)?;
+                        Err(std::io::Error::other(error))
+                    }
+                    Ok(_) => Err(std::io::Error::other(format!(
+                        "monitor exited unexpectedly, see {:?}",
+                        self.log_path()
+                    ))),
+                    Err(err) => Err(err),
+                };
+
+                match result {
+                    Ok(()) => {
+                        println!("{}", self.state.id);
+                        std::process::exit(0);
+                    }
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            ForkResult::Child => {
+                drop(read_end);
+
+                // Don't get killed along with the terminal's session
+                nix::unistd::setsid()?;
+
+                let null = File::open("/dev/null")?;
+                nix::unistd::dup2(null.as_raw_fd(), libc::STDIN_FILENO)?;
+                nix::unistd::dup2(log.as_raw_fd(), libc::STDOUT_FILENO)?;
+                nix::unistd::dup2(log.as_raw_fd(), libc::STDERR_FILENO)?;
+
+                self.state.monitor = std::process::id() as i32;
+                self.notify = Some(write_end);
+                self.save()?;
+
+                log::info!("Monitoring container {}", self.state.id);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Report the outcome of `start` to the CLI that detached us
+    fn notify(&mut self, result: &Result<Sandbox, std::io::Error>) {
+        let Some(mut notify) = self.notify.take() else {
+            return;
+        };
+
+        let message = match result {
+            Ok(_) => vec![STARTED],
+            Err(err) => [&[FAILED], err.to_string().as_bytes()].concat(),
+        };
+
+        if let Err(err) = notify.write_all(&message) {
+            log::warn!("Failed to notify the CLI: {err}");
+        }
+    }
+
+    /// Spawn the sandbox and record it as running
+    pub fn start(
+        &mut self,
+        root: &Root,
+        layers: &[PathBuf],
+        stdio: StdioConfig,
+    ) -> Result<Sandbox, std::io::Error> {
+        let result = Sandbox::spawn(root, self, layers, stdio);
+
+        match &result {
+            Ok(sandbox) => {
+                self.state.status = Status::Running;
+                self.state.pid = Some(sandbox.pid.as_raw());
+            }
+            Err(_) => self.state.status = Status::Exited,
+        }
+
+        // Record the state before telling the CLI, which may look it up
+        // right away
+        let saved = self.save();
+        self.notify(&result);
+
+        if result.is_err() && self.state.auto_remove {
+            self.remove()?;
+        }
+
+        saved?;
+        result
+    }
+
+    /// Wait for the container to exit, recording it's exit code
+    pub fn wait(&mut self, mut sandbox: Sandbox) -> Result<i32, std::io::Error> {
+        let code = sandbox.wait()?;
+        drop(sandbox);
+
+        log::info!("Container {} exited with {code}", self.state.id);
+
+        self.state.status = Status::Exited;
+        self.state.exit_code = Some(code);
+        self.save()?;
+
+        if self.state.auto_remove {
+            let _lock = self.lock_exit(FlockArg::LockExclusive)?;
+            self.remove()?;
+        }
+
+        Ok(code)
+    }
+
+    /// Signal the container's init, which forwards it to the command
+    pub fn signal(&self, signal: Signal) -> Result<(), std::io::Error> {
+        let pid = match (self.state.status, self.state.pid) {
+            (Status::Running, Some(pid)) => pid,
+            _ => {
+                return Err(std::io::Error::other(format!(
+                    "Container {} is not running",
+                    self.short_id()
+                )))
+            }
+        };
+
+        log::info!("Sending {signal} to {pid}");
+
+        match nix::sys::signal::kill(Pid::from_raw(pid), signal) {
+            // Exited meanwhile
+            Err(Errno::ESRCH) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    /// Wait for the owner to record the container's exit, giving up after
+    /// `timeout`. Returns whether it exited
+    pub fn wait_exited(&mut self, timeout: Option<Duration>) -> Result<bool, std::io::Error> {
+        const INTERVAL: Duration = Duration::from_millis(100);
+
+        let start = Instant::now();
+        let _watch = self.watch()?;
+
+        loop {
+            self.reload()?;
+
+            if self.state.status == Status::Exited {
+                return Ok(true);
+            }
+
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Ok(false);
+            }
+
+            std::thread::sleep(INTERVAL);
+        }
+    }
+
+    /// The image's `StopSignal`, SIGTERM if it doesn't set one
+    pub fn stop_signal(&self) -> Result<Signal, std::io::Error> {
+        match &self.state.config.stop_signal {
+            None => Ok(Signal::SIGTERM),
+            Some(signal) => util::parse_signal(signal).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid stop signal '{signal}'"),
+                )
+            }),
+        }
+    }
+
+    /// Ask the container to exit with it's stop signal, killing it if it
+    /// hasn't after `timeout`, or it's own stop timeout if `None`
+    pub fn stop(&mut self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        let timeout = timeout.unwrap_or(Duration::from_secs(self.state.stop_timeout));
+
+        if !self.is_running() {
+            return Ok(());
+        }
+
+        // Otherwise an `--rm` container could be gone before we see it exit
+        let _watch = self.watch()?;
+
+        self.signal(self.stop_signal()?)?;
+
+        if !self.wait_exited(Some(timeout))? {
+            log::warn!(
+                "Container {} didn't exit after {timeout:?}, killing it",
+                self.short_id()
+            );
+
+            self.signal(Signal::SIGKILL)?;
+            self.wait_exited(None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the state directory, along with the log and slirp's socket
+    /// The upper dir lives on a tmpfs in the container's mount namespace,
+    /// so it's already gone once the container exits
+    pub fn remove(&self) -> Result<(), std::io::Error> {
+        util::remove_tree(&self.dir)
+    }
+}
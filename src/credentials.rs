@@ -0,0 +1,346 @@
+use crate::{reference::Reference, util};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Don't leak the password into logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credentials {
+    /// Value for `Basic` authorization, base64(username:password)
+    pub fn encode(&self) -> String {
+        BASE64.encode(format!("{}:{}", self.username, self.password))
+    }
+
+    fn decode(encoded: &str) -> std::io::Result<Self> {
+        let decoded = BASE64
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Invalid base64 in auth entry",
+                )
+            })?;
+
+        match decoded.split_once(':') {
+            Some((username, password)) => Ok(Self {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Auth entry is not in the 'user:password' format",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct AuthEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<String>,
+    /// Any other keys, such as `identitytoken`, that we must preserve
+    #[serde(flatten)]
+    rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The subset of `~/.docker/config.json` and podman's `auth.json` that
+/// we understand, both share the same format for credentials
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct AuthConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    /// Per-registry credential helpers, `docker-credential-<helper>`
+    #[serde(
+        rename = "credHelpers",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    cred_helpers: HashMap<String, String>,
+    /// Credential helper for all registries without a specific helper
+    #[serde(
+        rename = "credsStore",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    creds_store: Option<String>,
+    /// Unrelated settings in docker's config that must survive a rewrite
+    #[serde(flatten)]
+    rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The credential helper protocol's representation of credentials
+/// https://github.com/docker/docker-credential-helpers
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    #[serde(rename = "ServerURL")]
+    server_url: String,
+    username: String,
+    secret: String,
+}
+
+/// Docker stores Docker Hub's credentials under the legacy v1 URL
+const DOCKER_HUB_KEY: &str = "https://index.docker.io/v1/";
+
+/// Reduce the various forms of keys used in `auths` to a bare registry host
+/// https://index.docker.io/v1/ -> docker.io
+fn normalize_key(key: &str) -> String {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+
+    Reference::normalize_registry(key.split('/').next().unwrap_or(key))
+}
+
+/// The key that `docker login` would've used for a registry
+fn key_for(registry: &str) -> String {
+    if Reference::normalize_registry(registry) == Reference::DOCKER_HUB {
+        DOCKER_HUB_KEY.to_string()
+    } else {
+        registry.to_string()
+    }
+}
+
+/// Podman's file takes precedence over docker's, as with podman itself
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    if let Ok(path) = std::env::var("REGISTRY_AUTH_FILE") {
+        paths.push(PathBuf::from(path));
+    }
+
+    if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+        paths.push(PathBuf::from(runtime_dir).join("containers/auth.json"));
+    }
+
+    paths.extend(docker_config_path());
+    paths
+}
+
+/// The file written by `dabba login`
+fn docker_config_path() -> Option<PathBuf> {
+    if let Ok(dir) = std::env::var("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir).join("config.json"));
+    }
+
+    std::env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".docker/config.json"))
+}
+
+fn read_config(path: &PathBuf) -> std::io::Result<Option<AuthConfig>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(util::serde_deserialize_or_err(&contents)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Run `docker-credential-<helper> <action>`, writing `input` to it's stdin
+fn run_helper(helper: &str, action: &str, input: &[u8]) -> std::io::Result<Vec<u8>> {
+    let program = format!("docker-credential-{helper}");
+    log::info!("Executing {program} {action}");
+
+    let mut child = Command::new(&program)
+        .arg(action)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .expect("failed to take stdin")
+        .write_all(input)?;
+
+    let output = util::wait_with_output(&mut child)?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{program} {action} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim()
+        )));
+    }
+
+    Ok(output.stdout)
+}
+
+impl AuthConfig {
+    fn helper_for(&self, registry: &str) -> Option<&String> {
+        self.cred_helpers
+            .iter()
+            .find(|(key, _)| normalize_key(key) == registry)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref())
+    }
+
+    fn lookup(&self, registry: &str) -> std::io::Result<Option<Credentials>> {
+        if let Some(helper) = self.helper_for(registry) {
+            let output = run_helper(helper, "get", key_for(registry).as_bytes())?;
+            let parsed: HelperCredentials =
+                util::serde_deserialize_or_err(&String::from_utf8_lossy(&output))?;
+
+            return Ok(Some(Credentials {
+                username: parsed.username,
+                password: parsed.secret,
+            }));
+        }
+
+        for (key, entry) in &self.auths {
+            if normalize_key(key) != registry {
+                continue;
+            }
+
+            if let Some(auth) = &entry.auth {
+                return Ok(Some(Credentials::decode(auth)?));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Find the credentials for a registry host in the docker/podman configs,
+/// falling back to anonymous access on any errors
+pub fn lookup(registry: &str) -> Option<Credentials> {
+    let registry = Reference::normalize_registry(registry);
+
+    for path in config_paths() {
+        let found = read_config(&path)
+            .and_then(|config| match config {
+                Some(config) => config.lookup(&registry),
+                None => Ok(None),
+            })
+            .unwrap_or_else(|err| {
+                log::warn!("Failed to read credentials from {path:?}: {err}");
+                None
+            });
+
+        if found.is_some() {
+            log::info!("Using credentials for {registry} from {path:?}");
+            return found;
+        }
+    }
+
+    None
+}
+
+fn write_config(path: &PathBuf, config: &AuthConfig) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let json = serde_json::to_string_pretty(config).expect("unreachable (unserializable type)");
+
+    // Write to a temporary file first so that a crash doesn't leave a
+    // truncated config behind
+    let tmp = path.with_extension("json.tmp");
+
+    // Left behind by a crash, `create_new` would refuse it
+    match std::fs::remove_file(&tmp) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    // Only readable by us from the start, the config holds passwords
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+
+    file.write_all(json.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(tmp, path)
+}
+
+fn docker_config() -> std::io::Result<(PathBuf, AuthConfig)> {
+    let path = docker_config_path()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "$HOME is not set"))?;
+    let config = read_config(&path)?.unwrap_or_default();
+
+    Ok((path, config))
+}
+
+/// Persist the credentials for a registry, through the configured credential
+/// helper if there is one, or into the `auths` section otherwise
+pub fn store(registry: &str, credentials: &Credentials) -> std::io::Result<()> {
+    let registry = Reference::normalize_registry(registry);
+    let (path, mut config) = docker_config()?;
+
+    if let Some(helper) = config.helper_for(&registry) {
+        let request = HelperCredentials {
+            server_url: key_for(&registry),
+            username: credentials.username.clone(),
+            secret: credentials.password.clone(),
+        };
+
+        let request = serde_json::to_vec(&request).expect("unreachable (unserializable type)");
+        run_helper(helper, "store", &request)?;
+
+        return Ok(());
+    }
+
+    // Replace any differently spelled entries for the same registry
+    config.auths.retain(|key, _| normalize_key(key) != registry);
+    config.auths.insert(
+        key_for(&registry),
+        AuthEntry {
+            auth: Some(credentials.encode()),
+            ..Default::default()
+        },
+    );
+
+    write_config(&path, &config)?;
+    log::info!("Stored credentials for {registry} in {path:?}");
+
+    Ok(())
+}
+
+/// Remove the stored credentials for a registry, returns whether any
+/// credentials existed
+pub fn erase(registry: &str) -> std::io::Result<bool> {
+    let registry = Reference::normalize_registry(registry);
+    let (path, mut config) = docker_config()?;
+
+    if let Some(helper) = config.helper_for(&registry) {
+        run_helper(helper, "erase", key_for(&registry).as_bytes())?;
+        return Ok(true);
+    }
+
+    let count = config.auths.len();
+    config.auths.retain(|key, _| normalize_key(key) != registry);
+
+    if config.auths.len() == count {
+        return Ok(false);
+    }
+
+    write_config(&path, &config)?;
+    log::info!("Removed credentials for {registry} from {path:?}");
+
+    Ok(true)
+}
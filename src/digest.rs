@@ -0,0 +1,207 @@
+use sha2::{Digest as _, Sha256, Sha512};
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Hash algorithms allowed in OCI content digests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+/// A content digest, `sha256:96526aa774ef...`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    pub hex: String,
+}
+
+impl FromStr for Digest {
+    type Err = std::io::Error;
+
+    fn from_str(digest: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid digest '{digest}': {reason}"),
+            )
+        };
+
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .ok_or_else(|| invalid("no algorithm"))?;
+
+        let (algorithm, len) = match algorithm {
+            "sha256" => (Algorithm::Sha256, 64),
+            "sha512" => (Algorithm::Sha512, 128),
+            _ => return Err(invalid("unsupported algorithm")),
+        };
+
+        if hex.len() != len || !hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+            return Err(invalid("malformed hex"));
+        }
+
+        Ok(Self {
+            algorithm,
+            hex: hex.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize_hex(&self) -> String {
+        let bytes = match self {
+            Self::Sha256(hasher) => hasher.clone().finalize().to_vec(),
+            Self::Sha512(hasher) => hasher.clone().finalize().to_vec(),
+        };
+
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+/// Hashes and counts everything read through it, so that content can be
+/// verified against its descriptor without buffering it
+pub struct VerifyingReader<R> {
+    inner: R,
+    hasher: Hasher,
+    expected: Digest,
+    expected_size: u64,
+    read: u64,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub fn new(inner: R, digest: &str, size: u64) -> std::io::Result<Self> {
+        let expected: Digest = digest.parse()?;
+
+        Ok(Self {
+            inner,
+            hasher: Hasher::new(expected.algorithm),
+            expected,
+            expected_size: size,
+            read: 0,
+        })
+    }
+
+    /// Check the content read so far against the descriptor, must be called
+    /// only after the inner reader has been exhausted
+    pub fn verify(&self) -> std::io::Result<()> {
+        if self.read != self.expected_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Size mismatch for {}: expected {} bytes, got {}",
+                    self.expected, self.expected_size, self.read
+                ),
+            ));
+        }
+
+        let actual = self.hasher.finalize_hex();
+
+        if actual != self.expected.hex {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Digest mismatch for {}: got {actual}", self.expected),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+
+        self.hasher.update(&buf[..count]);
+        self.read += count as u64;
+
+        // Bail out early rather than reading an endless stream
+        if self.read > self.expected_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} is larger than the expected {} bytes",
+                    self.expected, self.expected_size
+                ),
+            ));
+        }
+
+        Ok(count)
+    }
+}
+
+/// Verify an in-memory blob, such as a manifest, against its digest
+pub fn verify_bytes(bytes: &[u8], digest: &str) -> std::io::Result<()> {
+    let mut reader = VerifyingReader::new(bytes, digest, bytes.len() as u64)?;
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+
+    reader.verify()
+}
+
+/// Compute the digest of an in-memory blob, for content we didn't fetch by
+/// digest such as manifests pulled by tag
+pub fn sha256(bytes: &[u8]) -> String {
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    hasher.update(bytes);
+
+    format!("{}:{}", Algorithm::Sha256, hasher.finalize_hex())
+}
+
+/// Compute the digest and size of a stream, such as a layer tarball that
+/// we're importing
+pub fn sha256_reader(mut reader: impl Read) -> std::io::Result<(String, u64)> {
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    let mut buf = [0_u8; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let count = reader.read(&mut buf)?;
+
+        if count == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..count]);
+        size += count as u64;
+    }
+
+    Ok((
+        format!("{}:{}", Algorithm::Sha256, hasher.finalize_hex()),
+        size,
+    ))
+}
@@ -44,10 +44,7 @@ fn exec_cmd_with_mappings(
     let status = Command::new(cmd).args(args).status()?;
 
     if !status.success() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("{cmd} exited with {status}"),
-        ));
+        return Err(std::io::Error::other(format!("{cmd} exited with {status}")));
     }
 
     Ok(())
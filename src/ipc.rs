@@ -34,6 +34,11 @@ pub enum ParentEvent {
     UidGidMapFailure,
     // All good, child can go ahead with the requested command in the sandbox
     InitSuccess,
+    // The terminal requested by the child was allocated, followed by it's
+    // index in the child's /dev/pts
+    PtyAllocated,
+    // An error occured when allocating the terminal
+    PtyFailure,
 }
 
 fn send(write_fd: RawFd, event: u8) -> Result<(), std::io::Error> {
@@ -42,12 +47,25 @@ fn send(write_fd: RawFd, event: u8) -> Result<(), std::io::Error> {
 }
 
 fn recv(read_fd: RawFd) -> Result<u8, std::io::Error> {
-    let mut event = [std::u8::MAX];
+    let mut event = [u8::MAX];
     nix::unistd::read(read_fd, &mut event)?;
 
     Ok(event[0])
 }
 
+fn recv_exact(read_fd: RawFd, buf: &mut [u8]) -> Result<(), std::io::Error> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match nix::unistd::read(read_fd, &mut buf[read..])? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            count => read += count,
+        }
+    }
+
+    Ok(())
+}
+
 impl Ipc {
     pub fn new() -> Result<Self, std::io::Error> {
         Ok(Self {
@@ -64,6 +82,23 @@ impl Ipc {
         send(self.child_pipe.1.as_raw_fd(), event.into())
     }
 
+    /// Sent as a single write so that the child never sees just the event
+    pub fn send_pty_from_parent(&self, index: u32) -> Result<(), std::io::Error> {
+        let mut message = vec![ParentEvent::PtyAllocated.into()];
+        message.extend(index.to_ne_bytes());
+
+        nix::unistd::write(self.parent_pipe.1.as_raw_fd(), &message)?;
+        Ok(())
+    }
+
+    /// Read the index following `ParentEvent::PtyAllocated`
+    pub fn recv_pty_in_child(&self) -> Result<u32, std::io::Error> {
+        let mut index = [0; 4];
+        recv_exact(self.parent_pipe.0.as_raw_fd(), &mut index)?;
+
+        Ok(u32::from_ne_bytes(index))
+    }
+
     pub fn recv_in_parent(&self) -> Result<ChildEvent, std::io::Error> {
         Ok(ChildEvent::try_from(recv(self.child_pipe.0.as_raw_fd())?)
             .expect("got invalid value from pipe!"))
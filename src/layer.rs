@@ -0,0 +1,461 @@
+use flate2::read::GzDecoder;
+use nix::sys::stat::{Mode, SFlag};
+use std::ffi::CString;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+
+/// Compression applied to a layer tarball
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    const GZIP_MAGIC: &'static [u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &'static [u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+    /// `None` for media types we don't know about, the compression must be
+    /// guessed from the content in that case
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/vnd.oci.image.layer.v1.tar"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar" => Some(Self::None),
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+            | "application/vnd.docker.image.rootfs.diff.tar.gzip"
+            | "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip" => Some(Self::Gzip),
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Guess the compression from the first bytes of the blob
+    pub fn sniff(header: &[u8]) -> Self {
+        if header.starts_with(Self::GZIP_MAGIC) {
+            Self::Gzip
+        } else if header.starts_with(Self::ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Non-distributable (foreign) layers can't be pushed to or pulled from
+/// registries, they're expected to be fetched from elsewhere, which is
+/// mostly relevant for Windows base images
+pub fn is_foreign(media_type: &str) -> bool {
+    media_type.contains(".nondistributable.") || media_type.contains(".foreign.")
+}
+
+/// Prefix of files marking the deletion of a file from lower layers
+/// https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marks the directory containing it as opaque, hiding lower layers' entries
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// OverlayFS is mounted with `userxattr` inside the user namespace, so only
+/// the `user.` namespace is consulted, `trusted.` needs real root
+const OVERLAY_OPAQUE_XATTR: &str = "user.overlay.opaque";
+const OVERLAY_WHITEOUT_XATTR: &str = "user.overlay.whiteout";
+
+fn lgetxattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let name = CString::new(name).expect("unreachable, no NUL in name");
+
+    let mut value = [0_u8; 16];
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+
+    if len < 0 {
+        return None;
+    }
+
+    Some(value[..len as usize].to_vec())
+}
+
+fn lsetxattr(path: &Path, name: &str, value: &[u8]) -> Result<(), std::io::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+
+    // XXX nix doesn't provide safe wrappers for xattrs
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Mark a directory as opaque, so that OverlayFS hides the contents of the
+/// same directory in lower layers
+fn make_opaque(dir: &Path) -> Result<(), std::io::Error> {
+    lsetxattr(dir, OVERLAY_OPAQUE_XATTR, b"y")
+}
+
+/// Create an OverlayFS whiteout at `path`, hiding the file of the same name
+/// in lower layers
+/// The native format is a 0/0 character device, which unprivileged users
+/// may create since Linux 5.8. Failing that, we fall back to the xattr
+/// based format from Linux 6.7, an empty file with the whiteout xattr whose
+/// parent directory is marked with `opaque=x`
+fn make_whiteout(path: &Path) -> Result<(), std::io::Error> {
+    match nix::sys::stat::mknod(path, SFlag::S_IFCHR, Mode::empty(), 0) {
+        Ok(()) => return Ok(()),
+        Err(err) => log::debug!("Can't mknod whiteout {path:?}, using xattrs: {err}"),
+    }
+
+    // Never through a symlink or over an existing file, which could lie
+    // outside the layer
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    lsetxattr(path, OVERLAY_WHITEOUT_XATTR, b"")?;
+
+    let parent = path.parent().expect("unreachable, whiteout has a parent");
+
+    // A fully opaque directory already hides everything below it, and
+    // downgrading it would unhide those entries
+    if lgetxattr(parent, OVERLAY_OPAQUE_XATTR).as_deref() != Some(b"y") {
+        lsetxattr(parent, OVERLAY_OPAQUE_XATTR, b"x")?;
+    }
+
+    Ok(())
+}
+
+/// Only plain relative paths can be trusted, anything else could be used
+/// to write outside the layer's directory
+fn is_safe_path(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Create the directories of the relative `path` inside `dest` one by one,
+/// refusing to go through symlinks, which an earlier entry of the layer
+/// could have pointed anywhere on the host
+fn create_dirs_in(dest: &Path, path: &Path) -> Result<PathBuf, std::io::Error> {
+    let mut dir = dest.to_path_buf();
+
+    for component in path.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        dir.push(name);
+
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Whiteout parent {dir:?} is not a directory"),
+                ))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(&dir)?,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(dir)
+}
+
+/// Unpack a layer tarball into `dest`, translating OCI/AUFS whiteouts into
+/// the format understood by OverlayFS rather than extracting them as-is
+/// Directories are unpacked last, deepest first, same as `Archive::unpack`,
+/// as their permissions could otherwise deny creating entries inside them,
+/// such as a 0555 /usr/bin
+pub fn unpack(archive: impl Read, dest: &Path) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(dest)?;
+
+    let mut archive = Archive::new(archive);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    let mut directories = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push(entry);
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => {
+                entry.unpack_in(dest)?;
+                continue;
+            }
+        };
+
+        if !name.starts_with(WHITEOUT_PREFIX) {
+            entry.unpack_in(dest)?;
+            continue;
+        }
+
+        if !is_safe_path(&path) {
+            log::warn!("Skipping whiteout with unsafe path {path:?}");
+            continue;
+        }
+
+        let parent = create_dirs_in(dest, path.parent().unwrap_or(Path::new("")))?;
+
+        if name == OPAQUE_WHITEOUT {
+            log::debug!("Marking {parent:?} as opaque");
+            make_opaque(&parent)?;
+        } else {
+            let target = parent.join(&name[WHITEOUT_PREFIX.len()..]);
+            log::debug!("Creating whiteout {target:?}");
+
+            make_whiteout(&target)?;
+        }
+    }
+
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+
+    for mut directory in directories {
+        directory.unpack_in(dest)?;
+    }
+
+    Ok(())
+}
+
+/// Decompress and unpack a layer blob, picking the decompressor from the
+/// media type, or from the blob's magic bytes if the media type is unknown
+/// The blob is read till the end of the compressed stream
+pub fn unpack_blob(blob: impl Read, media_type: &str, dest: &Path) -> Result<(), std::io::Error> {
+    let compression = Compression::from_media_type(media_type);
+
+    if compression.is_none() {
+        log::warn!("Unknown layer media type {media_type}, guessing the compression");
+    }
+
+    unpack_compressed(blob, compression, dest)
+}
+
+/// Same as `unpack_blob`, the compression is guessed if it's `None`
+pub fn unpack_compressed(
+    blob: impl Read,
+    compression: Option<Compression>,
+    dest: &Path,
+) -> Result<(), std::io::Error> {
+    let mut blob = BufReader::new(blob);
+
+    let compression = match compression {
+        Some(compression) => compression,
+        None => {
+            let compression = Compression::sniff(blob.fill_buf()?);
+            log::debug!("Guessed compression {compression:?}");
+
+            compression
+        }
+    };
+
+    let mut reader: Box<dyn Read + '_> = match compression {
+        Compression::None => Box::new(blob),
+        Compression::Gzip => Box::new(GzDecoder::new(blob)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(blob)?),
+    };
+
+    unpack(&mut reader, dest)?;
+
+    // tar stops reading at the end-of-archive marker, but any trailing
+    // padding and the compression footer must still be consumed
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+    /// Build an uncompressed tarball from `(path, type, mode)` entries
+    fn tarball(entries: &[(&str, EntryType, u32)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        for (path, entry_type, mode) in entries {
+            let data = if *entry_type == EntryType::Regular {
+                path.as_bytes()
+            } else {
+                &[]
+            };
+
+            let mut header = tar::Header::new_gnu();
+            header.set_path(path).unwrap();
+            header.set_entry_type(*entry_type);
+            header.set_mode(*mode);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+
+            builder.append(&header, data).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dabba-layer-{name}-{}", std::process::id()));
+        crate::util::remove_tree(&dir).unwrap();
+
+        dir
+    }
+
+    fn is_whiteout(path: &Path) -> bool {
+        let metadata = std::fs::symlink_metadata(path).unwrap();
+
+        (metadata.file_type().is_char_device() && metadata.rdev() == 0)
+            || lgetxattr(path, OVERLAY_WHITEOUT_XATTR).is_some()
+    }
+
+    #[test]
+    fn whiteouts() {
+        let dest = scratch_dir("whiteouts");
+
+        unpack(
+            &tarball(&[
+                ("etc/", EntryType::Directory, 0o755),
+                ("etc/.wh.passwd", EntryType::Regular, 0o644),
+                ("etc/hosts", EntryType::Regular, 0o644),
+                ("var/lib/.wh.apt", EntryType::Regular, 0o644),
+            ])[..],
+            &dest,
+        )
+        .unwrap();
+
+        assert!(is_whiteout(&dest.join("etc/passwd")));
+        assert!(is_whiteout(&dest.join("var/lib/apt")));
+        assert!(!dest.join("etc/.wh.passwd").exists());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("etc/hosts")).unwrap(),
+            "etc/hosts"
+        );
+
+        crate::util::remove_tree(&dest).unwrap();
+    }
+
+    #[test]
+    fn opaque_whiteouts() {
+        let dest = scratch_dir("opaque");
+
+        unpack(
+            &tarball(&[
+                ("usr/share/", EntryType::Directory, 0o755),
+                ("usr/share/.wh..wh..opq", EntryType::Regular, 0o644),
+                ("usr/share/doc", EntryType::Regular, 0o644),
+                ("opt/.wh..wh..opq", EntryType::Regular, 0o644),
+            ])[..],
+            &dest,
+        )
+        .unwrap();
+
+        for dir in ["usr/share", "opt"] {
+            assert_eq!(
+                lgetxattr(&dest.join(dir), OVERLAY_OPAQUE_XATTR).as_deref(),
+                Some(&b"y"[..])
+            );
+            assert!(!dest.join(dir).join(OPAQUE_WHITEOUT).exists());
+        }
+        assert!(dest.join("usr/share/doc").is_file());
+
+        crate::util::remove_tree(&dest).unwrap();
+    }
+
+    #[test]
+    fn read_only_directories() {
+        let dest = scratch_dir("read-only");
+
+        unpack(
+            &tarball(&[
+                ("usr/", EntryType::Directory, 0o755),
+                ("usr/bin/", EntryType::Directory, 0o555),
+                ("usr/bin/sh", EntryType::Regular, 0o755),
+                ("usr/bin/.wh.bash", EntryType::Regular, 0o644),
+                ("usr/bin/.wh..wh..opq", EntryType::Regular, 0o644),
+            ])[..],
+            &dest,
+        )
+        .unwrap();
+
+        let bin = dest.join("usr/bin");
+        let metadata = std::fs::metadata(&bin).unwrap();
+
+        assert!(bin.join("sh").is_file());
+        assert!(is_whiteout(&bin.join("bash")));
+        assert_eq!(
+            lgetxattr(&bin, OVERLAY_OPAQUE_XATTR).as_deref(),
+            Some(&b"y"[..])
+        );
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o555);
+
+        crate::util::remove_tree(&dest).unwrap();
+    }
+
+    #[test]
+    fn whiteouts_through_symlinks() {
+        let dest = scratch_dir("symlink-dest");
+        let outside = scratch_dir("symlink-outside");
+
+        std::fs::create_dir_all(outside.join("sub")).unwrap();
+        std::fs::write(outside.join("bashrc"), "untouched").unwrap();
+
+        for whiteout in ["a/.wh.bashrc", "a/.wh..wh..opq", "a/sub/.wh.new"] {
+            let mut builder = tar::Builder::new(Vec::new());
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, "a", &outside).unwrap();
+
+            let mut header = tar::Header::new_gnu();
+            header.set_path(whiteout).unwrap();
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, &[][..]).unwrap();
+
+            let err = unpack(&builder.into_inner().unwrap()[..], &dest).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{whiteout}");
+
+            crate::util::remove_tree(&dest).unwrap();
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(outside.join("bashrc")).unwrap(),
+            "untouched"
+        );
+        assert!(!is_whiteout(&outside.join("bashrc")));
+        assert!(!outside.join("sub/new").exists());
+        for dir in [&outside, &outside.join("sub")] {
+            assert_eq!(lgetxattr(dir, OVERLAY_OPAQUE_XATTR), None);
+        }
+
+        crate::util::remove_tree(&outside).unwrap();
+    }
+}
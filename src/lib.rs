@@ -1,9 +1,19 @@
+pub mod auth;
 pub mod cgroups;
+pub mod container;
+pub mod credentials;
+pub mod digest;
 pub mod idmap_helper;
 pub mod ipc;
+pub mod layer;
 pub mod log;
 pub mod mount_helper;
+pub mod progress;
+pub mod pty;
+pub mod reference;
 pub mod registry;
+pub mod root;
+pub mod rootfs;
 pub mod sandbox;
 pub mod slirp;
 pub mod storage;
@@ -1,60 +1,911 @@
 use dabba::{
-    log::Logger, registry::RegistryClient, sandbox::Sandbox, slirp::PortMapping, storage::Storage,
+    container::{Container, Status},
+    credentials::{self, Credentials},
+    log::Logger,
+    reference::Reference,
+    registry::{ImageConfigRuntime, RegistryClient},
+    root::Root,
+    rootfs::Rootfs,
+    sandbox::{Sandbox, StdioConfig},
+    slirp::PortMapping,
+    storage::{PruneOptions, PullPolicy, Storage},
     util,
 };
 use log::LevelFilter;
-use std::path::Path;
+use nix::sys::signal::Signal;
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Logger::register(LevelFilter::Info)?;
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "\
+Usage:
+  dabba [--root DIR] <command>
+
+Commands:
+  dabba run <image> [--pull=always|missing|never] [OPTIONS] [COMMAND]...
+  dabba run --rootfs <DIR|TAR> [OPTIONS] [COMMAND]...
+  dabba exec [-it] [-e KEY=VAL] [-w DIR] <container> <COMMAND>...
+  dabba ps [-a]
+  dabba stop [-t SECONDS] <container>...
+  dabba kill [-s SIGNAL] <container>...
+  dabba wait <container>...
+  dabba rm [-f] <container>...
+  dabba images
+  dabba inspect <image>...
+  dabba rmi [-f] <image>...
+  dabba push <image> [REMOTE]
+  dabba tags [-l] <repository>[:tag]
+  dabba search <registry> [TERM]
+  dabba load [-i FILE|DIR] [-t NAME]
+  dabba save [-o FILE] <image>...
+  dabba system df
+  dabba system prune [-a] [--max-age DURATION] [--max-size SIZE]
+  dabba login [-u USERNAME] [-p PASSWORD | --password-stdin] [REGISTRY]
+  dabba logout [REGISTRY]
+
+Run options:
+  -p HOST:GUEST/PROTO   Forward a port, can be repeated
+  -e KEY=VAL            Set an environment variable, can be repeated
+  -i, --interactive     Keep stdin attached
+  -t, --tty             Allocate a terminal
+  -d, --detach          Run in the background and print the container's ID
+  --rm                  Remove the container once it exits
+  --stop-timeout SECS   Grace period after the stop signal before killing
+                        the container, 10 by default
+  -w, --workdir DIR     Override the working directory
+  --entrypoint BINARY   Override the entrypoint";
+
+/// Budget for pruning automatically after each pull, unset by default
+const MAX_STORAGE_ENV: &str = "DABBA_MAX_STORAGE";
+const MAX_IMAGE_AGE_ENV: &str = "DABBA_MAX_IMAGE_AGE";
+
+fn parse_size(size: &str) -> Result<u64> {
+    util::parse_size(size)
+        .ok_or_else(|| format!("Invalid size '{size}', expected e.g. 10GB").into())
+}
+
+fn parse_duration(duration: &str) -> Result<std::time::Duration> {
+    util::parse_duration(duration)
+        .ok_or_else(|| format!("Invalid duration '{duration}', expected e.g. 7d").into())
+}
 
-    log::info!("Creating base directory");
-    std::fs::create_dir_all(util::get_base_path())?;
+/// An unknown flag is reported along with the usage, rather than a panic
+fn invalid_arg(arg: &str) -> Box<dyn std::error::Error> {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Invalid arg: {arg}\n\n{USAGE}"),
+    )
+    .into()
+}
 
-    let mut args = std::env::args().skip(1);
+fn open_storage(root: &Root) -> Result<Storage> {
+    Ok(Storage::new(&root.storage_dir())?)
+}
 
-    let image = args.next().expect("no image passed!");
-    let tag = args.next().expect("no tag passed!");
+fn run(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut reference = None::<Reference>;
+    let mut rootfs = None::<PathBuf>;
+    let mut command = Vec::<String>::new();
 
     let mut ports = Vec::<PortMapping>::new();
     let mut env = Vec::<String>::new();
+    let mut pull = PullPolicy::default();
+    let mut entrypoint = None::<String>;
+    let mut working_dir = None::<String>;
+    let mut stdio = StdioConfig::default();
+    let mut detach = false;
+    let mut auto_remove = false;
+    let mut stop_timeout = Container::DEFAULT_STOP_TIMEOUT;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pull" => pull = args.next().ok_or("--pull needs a policy")?.parse()?,
+            _ if arg.starts_with("--pull=") => pull = arg["--pull=".len()..].parse()?,
+            "--rootfs" => rootfs = Some(PathBuf::from(args.next().ok_or("--rootfs needs a path")?)),
+            "--entrypoint" => entrypoint = Some(args.next().ok_or("--entrypoint needs a binary")?),
+            "-w" | "--workdir" => working_dir = Some(args.next().ok_or("-w needs a directory")?),
+            "-d" | "--detach" => detach = true,
+            "--rm" => auto_remove = true,
+            "--stop-timeout" => {
+                stop_timeout = args
+                    .next()
+                    .ok_or("--stop-timeout needs a number of seconds")?
+                    .parse()?
+            }
+            "-t" | "--tty" => stdio.tty = true,
+            "-i" | "--interactive" => stdio.interactive = true,
+            "-it" | "-ti" => {
+                stdio.tty = true;
+                stdio.interactive = true;
+            }
+            "-p" => ports.push(args.next().ok_or("-p needs a mapping")?.parse()?),
+            "-e" => env.push(args.next().ok_or("-e needs a variable")?),
+            "--" => command.extend(args.by_ref()),
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            // [registry[:port]/]repository[:tag][@digest]
+            _ if reference.is_none() && rootfs.is_none() => reference = Some(arg.parse()?),
+            // Everything after the image is the command
+            _ => {
+                command.push(arg);
+                command.extend(args.by_ref());
+            }
+        }
+    }
+
+    // Nothing to relay a terminal or our stdin to once we've exited
+    if detach && (stdio.tty || stdio.interactive) {
+        return Err("-d can't be combined with -i or -t".into());
+    }
+
+    // Held till the container exits
+    let mut lease = None;
+    let mut extracted = None;
+
+    let (image, layers, mut config) = match (reference, rootfs) {
+        (Some(_), Some(_)) => return Err("Pass either an image or --rootfs, not both".into()),
+        (None, None) => return Err("No image passed".into()),
+        (None, Some(path)) => {
+            log::info!("Using rootfs: {path:?}");
+
+            let rootfs = Rootfs::open(root, &path)?;
+            let layers = vec![rootfs.path().to_path_buf()];
+            extracted = Some(rootfs);
+
+            (
+                path.display().to_string(),
+                layers,
+                ImageConfigRuntime::default(),
+            )
+        }
+        (Some(reference), None) => {
+            log::info!("Using image: '{reference}'");
+            let storage = open_storage(root)?;
+
+            let (image, image_lease) = storage.get_image(&reference, pull)?;
+            lease = Some(image_lease);
+
+            // The image we're about to run is leased, so it's never pruned here
+            let auto_prune = PruneOptions {
+                all: false,
+                max_age: std::env::var(MAX_IMAGE_AGE_ENV)
+                    .ok()
+                    .map(|age| parse_duration(&age))
+                    .transpose()?,
+                max_size: std::env::var(MAX_STORAGE_ENV)
+                    .ok()
+                    .map(|size| parse_size(&size))
+                    .transpose()?,
+            };
+
+            if auto_prune.max_age.is_some() || auto_prune.max_size.is_some() {
+                let report = storage.prune(&auto_prune)?;
+                log::info!(
+                    "Pruned {} images, reclaimed {}",
+                    report.images.len(),
+                    util::human_size(report.reclaimed)
+                );
+            }
+
+            (reference.to_string(), image.layers, image.config.config)
+        }
+    };
+
+    // Same semantics as docker, overriding the entrypoint also drops the
+    // image's arguments to it
+    if let Some(entrypoint) = entrypoint {
+        config.entrypoint = Some(vec![entrypoint]);
+        config.cmd = command;
+    } else if !command.is_empty() {
+        config.cmd = command;
+    }
+
+    if working_dir.is_some() {
+        config.working_dir = working_dir;
+    }
+
+    let mut container =
+        Container::create(root, image, config, env, ports, auto_remove, stop_timeout)?;
+
+    if detach {
+        container.detach()?;
+    }
+
+    let sandbox = container.start(root, &layers, stdio)?;
+    let code = container.wait(sandbox)?;
+
+    // Like docker, exit with the container's status
+    if code != 0 && !detach {
+        drop(lease);
+        drop(extracted);
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+fn exec(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut id = None::<String>;
+    let mut command = Vec::<String>::new();
+
+    let mut env = Vec::<String>::new();
+    let mut working_dir = None::<String>;
+    let mut stdio = StdioConfig::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-w" | "--workdir" => working_dir = Some(args.next().ok_or("-w needs a directory")?),
+            "-t" | "--tty" => stdio.tty = true,
+            "-i" | "--interactive" => stdio.interactive = true,
+            "-it" | "-ti" => {
+                stdio.tty = true;
+                stdio.interactive = true;
+            }
+            "-e" => env.push(args.next().ok_or("-e needs a variable")?),
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            // Everything after the container is the command
+            _ => {
+                id = Some(arg);
+                command.extend(args.by_ref());
+            }
+        }
+    }
+
+    let id = id.ok_or("No container passed")?;
+
+    if command.is_empty() {
+        return Err("No command passed".into());
+    }
+
+    let container = Container::load(root, &id)?;
+    let code = Sandbox::exec(&container, &command, &env, working_dir.as_deref(), stdio)?;
+
+    if code != 0 {
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+fn ps(root: &Root, args: impl Iterator<Item = String>) -> Result<()> {
+    let mut all = false;
+
+    for arg in args {
+        match arg.as_str() {
+            "-a" | "--all" => all = true,
+            _ => return Err(invalid_arg(&arg)),
+        }
+    }
+
+    println!(
+        "{:<14} {:<30} {:<24} {:<24} {:<12} PORTS",
+        "CONTAINER ID", "IMAGE", "COMMAND", "CREATED", "STATUS"
+    );
+
+    for container in Container::list(root)? {
+        if !all && !container.is_running() {
+            continue;
+        }
+
+        let state = &container.state;
+
+        let mut command = state
+            .config
+            .entrypoint
+            .iter()
+            .flatten()
+            .chain(&state.config.cmd)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if command.chars().count() > 20 {
+            command = format!("{}...", command.chars().take(19).collect::<String>());
+        }
+
+        let status = match (state.status, state.exit_code) {
+            (Status::Exited, Some(code)) => format!("Exited ({code})"),
+            (Status::Exited, None) => String::from("Exited"),
+            (Status::Running, _) => String::from("Running"),
+            (Status::Created, _) => String::from("Created"),
+        };
+
+        println!(
+            "{:<14} {:<30} {:<24} {:<24} {:<12} {}",
+            container.short_id(),
+            state.image,
+            format!("\"{command}\""),
+            util::human_age(UNIX_EPOCH + Duration::from_secs(state.created)),
+            status,
+            state
+                .ports
+                .iter()
+                .map(PortMapping::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+
+    Ok(())
+}
+
+fn stop(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut timeout = None::<Duration>;
+    let mut ids = Vec::<String>::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" | "--time" => {
+                let secs = args.next().ok_or("-t needs a number of seconds")?;
+                timeout = Some(Duration::from_secs(secs.parse()?));
+            }
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => ids.push(arg),
+        }
+    }
+
+    for id in ids {
+        Container::load(root, &id)?.stop(timeout)?;
+        println!("{id}");
+    }
+
+    Ok(())
+}
+
+fn kill(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut signal = Signal::SIGKILL;
+    let mut ids = Vec::<String>::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" | "--signal" => {
+                let name = args.next().ok_or("-s needs a signal")?;
+                signal =
+                    util::parse_signal(&name).ok_or_else(|| format!("Invalid signal '{name}'"))?;
+            }
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => ids.push(arg),
+        }
+    }
+
+    for id in ids {
+        Container::load(root, &id)?.signal(signal)?;
+        println!("{id}");
+    }
+
+    Ok(())
+}
+
+fn wait(root: &Root, args: impl Iterator<Item = String>) -> Result<()> {
+    for id in args {
+        let mut container = Container::load(root, &id)?;
+        container.wait_exited(None)?;
+
+        match container.state.exit_code {
+            Some(code) => println!("{code}"),
+            None => return Err(format!("Exit code of '{id}' is unknown").into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn rm(root: &Root, args: impl Iterator<Item = String>) -> Result<()> {
+    let mut force = false;
+    let mut ids = Vec::<String>::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => ids.push(arg),
+        }
+    }
+
+    for id in ids {
+        let mut container = Container::load(root, &id)?;
+
+        if container.is_running() {
+            if !force {
+                return Err(format!("Container '{id}' is running, stop it or use -f").into());
+            }
+
+            let _watch = container.watch()?;
+
+            container.signal(Signal::SIGKILL)?;
+            container.wait_exited(None)?;
+        }
+
+        container.remove()?;
+        println!("{id}");
+    }
+
+    Ok(())
+}
+
+fn images(root: &Root) -> Result<()> {
+    let storage = open_storage(root)?;
+
+    println!(
+        "{:<40} {:<16} {:<14} {:<10} CREATED",
+        "REPOSITORY", "TAG", "DIGEST", "SIZE"
+    );
+
+    for (name, record) in storage.images()? {
+        let reference: Reference = name.parse()?;
+
+        let (manifest, config) = match storage.load(&record) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                log::warn!("Skipping '{name}', can't read it's metadata: {err}");
+                continue;
+            }
+        };
+
+        let size =
+            manifest.config.size + manifest.layers.iter().map(|layer| layer.size).sum::<u64>();
+        let created = config
+            .created
+            .as_deref()
+            .and_then(util::parse_rfc3339)
+            .map(util::human_age)
+            .unwrap_or_else(|| String::from("N/A"));
+
+        // sha256:96526aa774ef...
+        let digest = record
+            .manifest
+            .split(':')
+            .nth(1)
+            .unwrap_or(&record.manifest);
+
+        println!(
+            "{:<40} {:<16} {:<14} {:<10} {created}",
+            format!("{}/{}", reference.registry, reference.repository),
+            reference.tag.as_deref().unwrap_or("<none>"),
+            &digest[..12.min(digest.len())],
+            util::human_size(size),
+        );
+    }
 
-    loop {
-        if let Some(arg) = args.next() {
-            match arg.as_str() {
-                "-p" => {
-                    if let Some(mapping) = args.next() {
-                        ports.push(PortMapping::from_str(&mapping));
-                        continue;
+    Ok(())
+}
+
+fn inspect(root: &Root, args: impl Iterator<Item = String>) -> Result<()> {
+    let storage = open_storage(root)?;
+    let mut out = Vec::new();
+
+    for arg in args {
+        let reference: Reference = arg.parse()?;
+
+        let Some(record) = storage.lookup(&reference)? else {
+            return Err(format!("No such image: '{reference}'").into());
+        };
+
+        let (manifest, config) = storage.load(&record)?;
+
+        out.push(serde_json::json!({
+            "Reference": reference.to_string(),
+            "Id": record.config,
+            "ManifestDigest": record.manifest,
+            "Created": config.created,
+            "Architecture": config.architecture,
+            "Os": config.os,
+            "Size": manifest.config.size + manifest.layers.iter().map(|layer| layer.size).sum::<u64>(),
+            "Config": {
+                "Env": config.config.env,
+                "Entrypoint": config.config.entrypoint,
+                "Cmd": config.config.cmd,
+                "WorkingDir": config.config.working_dir,
+                "ExposedPorts": config.config.exposed_ports,
+                "StopSignal": config.config.stop_signal,
+            },
+            "Labels": config.config.labels,
+            "Layers": manifest.layers.iter().map(|layer| serde_json::json!({
+                "Digest": layer.digest,
+                "MediaType": layer.media_type,
+                "Size": layer.size,
+            })).collect::<Vec<_>>(),
+            "History": config.history.iter().map(|step| serde_json::json!({
+                "Created": step.created,
+                "CreatedBy": step.created_by,
+                "Comment": step.comment,
+                "EmptyLayer": step.empty_layer,
+            })).collect::<Vec<_>>(),
+        }));
+    }
+
+    println!("{}", serde_json::to_string_pretty(&out)?);
+
+    Ok(())
+}
+
+fn rmi(root: &Root, args: impl Iterator<Item = String>) -> Result<()> {
+    let storage = open_storage(root)?;
+    let mut force = false;
+    let mut references = Vec::<Reference>::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => references.push(arg.parse()?),
+        }
+    }
+
+    for reference in references {
+        storage.remove(&reference, force)?;
+        println!("Untagged: {reference}");
+    }
+
+    Ok(())
+}
+
+fn tags(args: impl Iterator<Item = String>) -> Result<()> {
+    let mut long = false;
+    let mut name = None::<String>;
+
+    for arg in args {
+        match arg.as_str() {
+            "-l" | "--long" => long = true,
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => name = Some(arg),
+        }
+    }
+
+    let name = name.ok_or("No repository passed")?;
+    let reference: Reference = name.parse()?;
+    let registry = RegistryClient::new(&reference);
+
+    // `Reference` defaults to `latest`, only describe the tag or digest if
+    // it was explicitly asked for
+    let requested = name
+        .rsplit('/')
+        .next()
+        .is_some_and(|last| last.contains([':', '@']))
+        .then(|| reference.manifest_reference().to_string());
+
+    let tags = match requested {
+        Some(tag) => vec![tag],
+        None if long => registry.list_tags()?,
+        None => {
+            for tag in registry.list_tags()? {
+                println!("{tag}");
+            }
+
+            return Ok(());
+        }
+    };
+
+    println!("{:<24} {:<72} PLATFORMS", "TAG", "DIGEST");
+
+    for tag in tags {
+        let info = registry.describe(&tag)?;
+        println!(
+            "{tag:<24} {:<72} {}",
+            info.digest,
+            info.platforms.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn search(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let registry = args.next().ok_or("No registry passed")?;
+    let term = args.next().unwrap_or_default();
+
+    let repositories = RegistryClient::for_catalog(&registry)
+        .catalog()
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::NotFound => {
+                format!("{registry} doesn't allow listing it's repositories: {err}")
+            }
+            _ => err.to_string(),
+        })?;
+
+    for repository in repositories {
+        if repository.contains(&term) {
+            println!("{repository}");
+        }
+    }
+
+    Ok(())
+}
+
+fn push(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let local: Reference = args.next().ok_or("No image passed")?.parse()?;
+
+    let remote: Reference = match args.next() {
+        Some(remote) => remote.parse()?,
+        None => local.clone(),
+    };
+
+    let storage = open_storage(root)?;
+    let registry = RegistryClient::for_push(&remote, Some(&local));
+
+    log::info!("Pushing '{local}' to '{remote}'");
+    let digest = storage.push(&local, &registry)?;
+
+    println!("Pushed {remote}, digest: {digest}");
+
+    Ok(())
+}
+
+fn load(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let storage = open_storage(root)?;
+    let mut input = None;
+    let mut name = None::<Reference>;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-i" | "--input" => input = Some(PathBuf::from(args.next().ok_or("-i needs a path")?)),
+            "-t" | "--tag" => name = Some(args.next().ok_or("-t needs a name")?.parse()?),
+            _ => return Err(invalid_arg(&arg)),
+        }
+    }
+
+    let imported = match input {
+        Some(path) if path.is_dir() => storage.import_dir(&path, name.as_ref())?,
+        Some(path) => storage.import_tar(std::fs::File::open(path)?, name.as_ref())?,
+        None => storage.import_tar(std::io::stdin().lock(), name.as_ref())?,
+    };
+
+    for reference in imported {
+        println!("Loaded image: {reference}");
+    }
+
+    Ok(())
+}
+
+fn save(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let storage = open_storage(root)?;
+    let mut output = None;
+    let mut references = Vec::<Reference>::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().ok_or("-o needs a path")?),
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => references.push(arg.parse()?),
+        }
+    }
+
+    if references.is_empty() {
+        return Err("No images passed".into());
+    }
+
+    match output {
+        Some(path) => {
+            let file = std::fs::File::create(&path)?;
+
+            if let Err(err) = storage.export(&references, std::io::BufWriter::new(file)) {
+                std::fs::remove_file(&path).ok();
+                return Err(err.into());
+            }
+        }
+        None if std::io::stdout().is_terminal() => {
+            return Err("Refusing to write an archive to a terminal, pass -o or redirect".into());
+        }
+        None => storage.export(&references, std::io::stdout().lock())?,
+    }
+
+    Ok(())
+}
+
+fn system(root: &Root, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let storage = open_storage(root)?;
+
+    match args.next().as_deref() {
+        Some("df") => {
+            let usage = storage.usage()?;
+
+            println!(
+                "{:<20} {:<8} {:<8} {:<10} RECLAIMABLE",
+                "TYPE", "TOTAL", "ACTIVE", "SIZE"
+            );
+            println!(
+                "{:<20} {:<8} {:<8} {:<10} {}",
+                "Images",
+                usage.images,
+                usage.active,
+                util::human_size(usage.images_size),
+                util::human_size(usage.images_reclaimable)
+            );
+            println!(
+                "{:<20} {:<8} {:<8} {:<10} {}",
+                "Unreferenced",
+                usage.unreferenced,
+                0,
+                util::human_size(usage.unreferenced_size),
+                util::human_size(usage.unreferenced_size)
+            );
+            println!(
+                "{:<20} {:<8} {:<8} {:<10} {}",
+                "Stale downloads",
+                usage.stale_downloads,
+                0,
+                util::human_size(usage.stale_downloads_size),
+                util::human_size(usage.stale_downloads_size)
+            );
+        }
+        Some("prune") => {
+            let mut options = PruneOptions::default();
+
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-a" | "--all" => options.all = true,
+                    "--max-age" => {
+                        options.max_age = Some(parse_duration(
+                            &args.next().ok_or("--max-age needs a value")?,
+                        )?)
                     }
-                }
-                "-e" => {
-                    if let Some(env_var) = args.next() {
-                        env.push(env_var);
-                        continue;
+                    "--max-size" => {
+                        options.max_size =
+                            Some(parse_size(&args.next().ok_or("--max-size needs a value")?)?)
                     }
+                    _ => return Err(invalid_arg(&arg)),
                 }
-                _ => panic!("Invalid arg: {arg}"),
             }
+
+            let report = storage.prune(&options)?;
+
+            for image in &report.images {
+                println!("Untagged: {image}");
+            }
+
+            println!(
+                "Deleted {} items, total reclaimed space: {}",
+                report.deleted,
+                util::human_size(report.reclaimed)
+            );
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a line from stdin after printing `prompt`, optionally with echo
+/// disabled for secrets
+fn prompt(prompt: &str, hidden: bool) -> Result<String> {
+    let stdin = std::io::stdin();
+
+    eprint!("{prompt}");
+    std::io::stderr().flush()?;
+
+    // Only touch the terminal attributes if stdin is actually a terminal
+    let saved = if hidden {
+        termios::tcgetattr(&stdin).ok()
+    } else {
+        None
+    };
+
+    if let Some(saved) = &saved {
+        let mut attrs = saved.clone();
+        attrs.local_flags.remove(LocalFlags::ECHO);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &attrs)?;
+    }
+
+    let mut line = String::new();
+    let read = stdin.lock().read_line(&mut line);
+
+    if let Some(saved) = &saved {
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, saved)?;
+        eprintln!();
+    }
+
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn login(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut username = None;
+    let mut password = None;
+    let mut registry = Reference::DOCKER_HUB.to_string();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-u" | "--username" => username = args.next(),
+            "-p" | "--password" => password = args.next(),
+            "--password-stdin" => password = Some(prompt("", false)?),
+            _ if arg.starts_with('-') => return Err(invalid_arg(&arg)),
+            _ => registry = arg,
         }
+    }
+
+    let username = match username {
+        Some(username) => username,
+        None => prompt("Username: ", false)?,
+    };
+
+    let password = match password {
+        Some(password) => password,
+        None => prompt("Password: ", true)?,
+    };
+
+    let credentials = Credentials { username, password };
+
+    log::info!("Verifying credentials for {registry}");
+    RegistryClient::login(&registry, &credentials)?;
 
-        // The inner matches will continue the loop if valid data exists
-        break;
+    credentials::store(&registry, &credentials)?;
+    println!("Login Succeeded");
+
+    Ok(())
+}
+
+fn logout(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let registry = args
+        .next()
+        .unwrap_or_else(|| Reference::DOCKER_HUB.to_string());
+
+    if credentials::erase(&registry)? {
+        println!("Removed login credentials for {registry}");
+    } else {
+        println!("Not logged in to {registry}");
+    }
+
+    Ok(())
+}
+
+fn dispatch(mut args: std::iter::Peekable<impl Iterator<Item = String>>) -> Result<()> {
+    // Global flags come before the command
+    let mut root = None;
+
+    while let Some(arg) = args.next_if(|arg| arg.starts_with("--root")) {
+        root = match arg.strip_prefix("--root=") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => Some(PathBuf::from(args.next().ok_or("--root needs a value")?)),
+        };
     }
 
-    log::info!("Using image: '{image}', tag: '{tag}'");
-    let registry = RegistryClient::new(&image, &tag);
+    let root = match root {
+        Some(dir) => Root::new(&dir),
+        None => Root::from_env(),
+    };
 
-    let manifest = registry.get_manifest()?;
-    log::info!("Image Manifest: {manifest:#?}");
+    log::info!("Using root {root:?}");
+    root.create()?;
 
-    let config = registry.get_image_config(&manifest)?;
-    log::info!("Image Config: {config:#?}");
+    match args.next().as_deref() {
+        Some("run") => run(&root, args),
+        Some("exec") => exec(&root, args),
+        Some("ps") => ps(&root, args),
+        Some("stop") => stop(&root, args),
+        Some("kill") => kill(&root, args),
+        Some("wait") => wait(&root, args),
+        Some("rm") => rm(&root, args),
+        Some("images") => images(&root),
+        Some("inspect") => inspect(&root, args),
+        Some("rmi") => rmi(&root, args),
+        Some("push") => push(&root, args),
+        Some("tags") => tags(args),
+        Some("search") => search(args),
+        Some("load") => load(&root, args),
+        Some("save") => save(&root, args),
+        Some("system") => system(&root, args),
+        Some("login") => login(args),
+        Some("logout") => logout(args),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
 
-    let storage = Storage::new(Path::new(&format!("{}/storage", util::get_base_path())))?;
-    let layers = storage.download_layers(&registry, &manifest)?;
+fn main() -> Result<()> {
+    Logger::register(LevelFilter::Info)?;
 
-    Sandbox::spawn(&layers, &config.config, &ports, &env)?;
+    // Returning the error from main would print it's Debug representation
+    if let Err(err) = dispatch(std::env::args().skip(1).peekable()) {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
 
     Ok(())
 }
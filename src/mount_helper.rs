@@ -61,8 +61,10 @@ pub fn mount_image(layers: &[PathBuf], merged: &Path) -> Result<(), std::io::Err
         // Escape the digest as ':' is the delimiter for directories
         .replace("sha256:", "sha256\\:");
 
+    // `userxattr` makes OverlayFS read opaque directories and whiteouts
+    // from the `user.overlay.*` xattrs, which we can set without privileges
     let mount_args = format!(
-        "lowerdir={},upperdir={},workdir={}",
+        "lowerdir={},upperdir={},workdir={},userxattr",
         lowerdir,
         upperdir.to_str().expect("invalid utf8!"),
         workdir.to_str().expect("invalid utf8!")
@@ -98,6 +100,7 @@ pub fn bind(
         // Read Only, doesn't matter as the bind mount sets permissions
         .mode(0o444)
         .create(true)
+        .truncate(false)
         .open(to_path)?;
 
     nix::mount::mount(
@@ -0,0 +1,211 @@
+use crate::util;
+use std::io::{IsTerminal, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Waiting,
+    Cached,
+    Downloading,
+    Extracting,
+    Done,
+    Failed,
+}
+
+struct Layer {
+    /// Shortened digest, as displayed by docker
+    name: String,
+    total: u64,
+    done: u64,
+    status: Status,
+    /// Bytes that were already present from an earlier partial download,
+    /// excluded from the transfer rate
+    resumed_from: u64,
+    started: Instant,
+    /// Last quarter of the download that was logged in non-TTY mode
+    logged_quarter: u64,
+}
+
+struct State {
+    layers: Vec<Layer>,
+    /// Lines drawn by the last render, to move the cursor back over them
+    lines_drawn: usize,
+    last_render: Option<Instant>,
+}
+
+/// Per-layer progress of a pull, redrawn in place on a TTY and logged at
+/// coarse intervals otherwise
+pub struct Progress {
+    tty: bool,
+    state: Mutex<State>,
+}
+
+impl Progress {
+    const RENDER_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// `layers` are (digest, size) pairs
+    pub fn new(layers: &[(&str, u64)]) -> Self {
+        let now = Instant::now();
+
+        Self {
+            tty: std::io::stderr().is_terminal(),
+            state: Mutex::new(State {
+                layers: layers
+                    .iter()
+                    .map(|(digest, total)| Layer {
+                        name: digest
+                            .rsplit(':')
+                            .next()
+                            .unwrap_or(digest)
+                            .chars()
+                            .take(12)
+                            .collect(),
+                        total: *total,
+                        done: 0,
+                        status: Status::Waiting,
+                        resumed_from: 0,
+                        started: now,
+                        logged_quarter: 0,
+                    })
+                    .collect(),
+                lines_drawn: 0,
+                last_render: None,
+            }),
+        }
+    }
+
+    pub fn set_status(&self, idx: usize, status: Status) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        let layer = &mut state.layers[idx];
+
+        layer.status = status;
+
+        if !self.tty {
+            log::info!("{}: {status:?}", layer.name);
+        }
+
+        self.render(&mut state, true);
+    }
+
+    /// Mark the start of a download, `offset` bytes are already present
+    pub fn start_download(&self, idx: usize, offset: u64) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        let layer = &mut state.layers[idx];
+
+        layer.done = offset;
+        layer.resumed_from = offset;
+        layer.started = Instant::now();
+
+        drop(state);
+        self.set_status(idx, Status::Downloading);
+    }
+
+    pub fn advance(&self, idx: usize, bytes: u64) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        let layer = &mut state.layers[idx];
+
+        layer.done += bytes;
+
+        if !self.tty && layer.total > 0 {
+            let quarter = layer.done * 4 / layer.total;
+
+            if quarter > layer.logged_quarter && quarter < 4 {
+                layer.logged_quarter = quarter;
+                log::info!("{}: {}", layer.name, Self::describe(layer));
+            }
+        }
+
+        self.render(&mut state, false);
+    }
+
+    /// Draw the final state, must be called once all layers are processed
+    pub fn finish(&self) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        self.render(&mut state, true);
+    }
+
+    fn describe(layer: &Layer) -> String {
+        let elapsed = layer.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            ((layer.done - layer.resumed_from) as f64 / elapsed) as u64
+        } else {
+            0
+        };
+
+        format!(
+            "{}/{} ({}/s)",
+            util::human_size(layer.done),
+            util::human_size(layer.total),
+            util::human_size(rate)
+        )
+    }
+
+    fn render(&self, state: &mut State, force: bool) {
+        if !self.tty {
+            return;
+        }
+
+        if !force
+            && state
+                .last_render
+                .is_some_and(|last| last.elapsed() < Self::RENDER_INTERVAL)
+        {
+            return;
+        }
+
+        let mut out = String::new();
+
+        // Move back up over the previous render
+        if state.lines_drawn > 0 {
+            out.push_str(&format!("\x1b[{}A", state.lines_drawn));
+        }
+
+        for layer in &state.layers {
+            let details = match layer.status {
+                Status::Downloading => Self::describe(layer),
+                Status::Extracting | Status::Done => util::human_size(layer.total),
+                _ => String::new(),
+            };
+
+            out.push_str(&format!(
+                "\r\x1b[K{}: {:<12} {details}\n",
+                layer.name,
+                format!("{:?}", layer.status)
+            ));
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        stderr.write_all(out.as_bytes()).ok();
+        stderr.flush().ok();
+
+        state.lines_drawn = state.layers.len();
+        state.last_render = Some(Instant::now());
+    }
+}
+
+/// Reports everything read through it as progress for a layer
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+    idx: usize,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a Progress, idx: usize) -> Self {
+        Self {
+            inner,
+            progress,
+            idx,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.progress.advance(self.idx, count as u64);
+
+        Ok(count)
+    }
+}
@@ -0,0 +1,223 @@
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd::Pid;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+
+/// Sent to the container when our stdin hits EOF, the terminal translates
+/// it to an EOF for the reader in canonical mode
+const VEOF: u8 = 0x04;
+
+/// Puts our terminal in raw mode so that every keystroke, including ^C,
+/// reaches the container as is. Restored once dropped
+struct RawMode {
+    saved: Termios,
+}
+
+impl RawMode {
+    fn enable() -> nix::Result<Self> {
+        let stdin = std::io::stdin();
+        let saved = termios::tcgetattr(&stdin)?;
+
+        let mut raw = saved.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(err) = termios::tcsetattr(std::io::stdin(), SetArg::TCSANOW, &self.saved) {
+            log::warn!("Failed to restore the terminal: {err}");
+        }
+    }
+}
+
+fn ioctl_result(ret: libc::c_int) -> Result<(), std::io::Error> {
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Master side of a pseudo terminal allocated from the container's private
+/// devpts instance, the container only ever sees it's own terminals
+pub struct Pty {
+    master: File,
+    /// Number of the other side in the container's `/dev/pts`
+    index: u32,
+}
+
+impl Pty {
+    /// Allocate a terminal from the devpts instance mounted in `pid`'s
+    /// `/dev/pts`. The container controls what the path resolves to, so
+    /// it's resolved as if `pid`'s root was ours, without following symlinks
+    pub fn open(pid: Pid) -> Result<Self, std::io::Error> {
+        let root = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(format!("/proc/{pid}/root"))?;
+
+        // XXX nix's `PtyMaster` can only be created by `posix_openpt`, which
+        // always opens the host's /dev/ptmx, and nix doesn't wrap openat2()
+        let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+        how.flags = (libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) as u64;
+        how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_SYMLINKS;
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                root.as_raw_fd(),
+                c"dev/pts/ptmx".as_ptr(),
+                &how,
+                std::mem::size_of::<libc::open_how>(),
+            )
+        };
+
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let master = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let unlock: libc::c_int = 0;
+        ioctl_result(unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSPTLCK, &unlock) })?;
+
+        let mut index: libc::c_uint = 0;
+        ioctl_result(unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCGPTN, &mut index) })?;
+
+        log::info!("Allocated /dev/pts/{index} for {pid}");
+
+        Ok(Self { master, index })
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Make `/dev/pts/<index>` the controlling terminal and the standard
+    /// streams of the calling process, which must be a session leader
+    /// Called inside the container
+    pub fn attach(index: u32) -> Result<(), std::io::Error> {
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(format!("/dev/pts/{index}"))?;
+
+        ioctl_result(unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY, 0) })?;
+
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            nix::unistd::dup2(slave.as_raw_fd(), fd)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the size of our terminal to the container's, nothing to do if
+    /// we aren't running in one
+    fn resize(&self) {
+        // XXX nix doesn't provide wrappers for the window size ioctls
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == -1 {
+            return;
+        }
+
+        if let Err(err) =
+            ioctl_result(unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) })
+        {
+            log::warn!("Failed to resize the terminal: {err}");
+        }
+    }
+
+    /// Forward our stdin (if `stdin` is set) to the container, and it's
+    /// output to our stdout, until the container closes the terminal
+    /// Our terminal is put in raw mode meanwhile, and resizes of it are
+    /// forwarded to the container
+    pub fn relay(&self, stdin: bool) -> Result<(), std::io::Error> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGWINCH);
+        mask.thread_block()?;
+
+        let mut winch =
+            SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK)?;
+        self.resize();
+
+        let input = std::io::stdin();
+        let _raw = if stdin && input.is_terminal() {
+            Some(RawMode::enable()?)
+        } else {
+            None
+        };
+
+        let mut stdin_open = stdin;
+        let mut master = &self.master;
+        let mut stdout = std::io::stdout().lock();
+        let mut buf = [0_u8; 8192];
+
+        loop {
+            let mut fds = vec![
+                PollFd::new(&self.master, PollFlags::POLLIN),
+                PollFd::new(&winch, PollFlags::POLLIN),
+            ];
+
+            if stdin_open {
+                fds.push(PollFd::new(&input, PollFlags::POLLIN));
+            }
+
+            match nix::poll::poll(&mut fds, -1) {
+                Err(Errno::EINTR) => continue,
+                result => result?,
+            };
+
+            let ready: Vec<bool> = fds
+                .iter()
+                .map(|fd| fd.revents().is_some_and(|events| !events.is_empty()))
+                .collect();
+            drop(fds);
+
+            if ready[0] {
+                match master.read(&mut buf) {
+                    // Every process in the container closed the terminal
+                    Ok(0) => break,
+                    Err(err) if err.raw_os_error() == Some(libc::EIO) => break,
+                    Err(err) => return Err(err),
+                    Ok(count) => {
+                        stdout.write_all(&buf[..count])?;
+                        stdout.flush()?;
+                    }
+                }
+            }
+
+            if ready[1] {
+                while winch.read_signal()?.is_some() {}
+                self.resize();
+            }
+
+            // Read directly rather than through `Stdin`, which may buffer
+            // more than it returns and leave poll() with nothing to report
+            if ready.get(2).copied().unwrap_or(false) {
+                match nix::unistd::read(input.as_raw_fd(), &mut buf)? {
+                    0 => {
+                        stdin_open = false;
+                        master.write_all(&[VEOF])?;
+                    }
+                    count => master.write_all(&buf[..count])?,
+                }
+            }
+        }
+
+        mask.thread_unblock()?;
+
+        Ok(())
+    }
+}
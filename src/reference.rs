@@ -0,0 +1,329 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A fully qualified image reference, as accepted by `docker pull`
+/// [registry[:port]/]repository[:tag][@digest]
+/// ghcr.io/org/app:1.2@sha256:...
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    /// Host (and optional port) of the registry, `docker.io` for Docker Hub
+    pub registry: String,
+    /// Path of the repository inside the registry, `library/alpine`
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    pub const DOCKER_HUB: &'static str = "docker.io";
+    const DOCKER_HUB_API: &'static str = "registry-1.docker.io";
+
+    const DEFAULT_TAG: &'static str = "latest";
+
+    pub fn is_docker_hub(&self) -> bool {
+        self.registry == Self::DOCKER_HUB
+    }
+
+    /// Registries on the loopback interface are spoken to over plain HTTP,
+    /// matching docker's behaviour for local development registries
+    pub fn is_insecure_registry(registry: &str) -> bool {
+        // The port follows the last ':', unless that is inside an IPv6
+        // address in brackets
+        let host = match registry.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => host,
+            _ => registry,
+        };
+
+        host == "localhost" || host == "127.0.0.1" || host == "[::1]"
+    }
+
+    /// The base URL of the `/v2/` API of the given registry host
+    pub fn registry_api_url(registry: &str) -> String {
+        let registry = Self::normalize_registry(registry);
+
+        let host = if registry == Self::DOCKER_HUB {
+            Self::DOCKER_HUB_API
+        } else {
+            &registry
+        };
+
+        let scheme = if Self::is_insecure_registry(&registry) {
+            "http"
+        } else {
+            "https"
+        };
+
+        format!("{scheme}://{host}/v2")
+    }
+
+    pub fn api_url(&self) -> String {
+        Self::registry_api_url(&self.registry)
+    }
+
+    /// The tag or digest to request the manifest with, the digest takes
+    /// precedence as it pins the exact content
+    pub fn manifest_reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or(Self::DEFAULT_TAG)
+    }
+
+    /// Normalize the various aliases for Docker Hub
+    pub fn normalize_registry(registry: &str) -> String {
+        match registry {
+            "index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => {
+                Self::DOCKER_HUB.to_string()
+            }
+            _ => registry.to_string(),
+        }
+    }
+
+    /// The first component is treated as a registry only if it looks like a
+    /// hostname, otherwise `org/app` would be treated as the `org` host
+    fn is_registry_component(component: &str) -> bool {
+        component.contains('.') || component.contains(':') || component == "localhost"
+    }
+
+    fn is_valid_path_component(component: &str) -> bool {
+        !component.is_empty()
+            && component.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-')
+            })
+    }
+
+    fn is_valid_tag(tag: &str) -> bool {
+        !tag.is_empty()
+            && tag.len() <= 128
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    }
+
+    fn is_valid_digest(digest: &str) -> bool {
+        match digest.split_once(':') {
+            Some((algorithm, hex)) => {
+                !algorithm.is_empty()
+                    && !hex.is_empty()
+                    && hex.chars().all(|c| c.is_ascii_hexdigit())
+            }
+            None => false,
+        }
+    }
+}
+
+fn invalid(reference: &str, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Invalid image reference '{reference}': {reason}"),
+    )
+}
+
+impl FromStr for Reference {
+    type Err = std::io::Error;
+
+    fn from_str(reference: &str) -> Result<Self, Self::Err> {
+        // The digest is unambiguous as '@' can't appear anywhere else
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => {
+                if !Self::is_valid_digest(digest) {
+                    return Err(invalid(reference, "malformed digest"));
+                }
+
+                (name, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+
+        // A ':' after the last '/' separates the tag, any ':' before that
+        // belongs to the registry's port
+        let (name, tag) = match name.rfind(':') {
+            Some(idx) if !name[idx..].contains('/') => {
+                let tag = &name[idx + 1..];
+
+                if !Self::is_valid_tag(tag) {
+                    return Err(invalid(reference, "malformed tag"));
+                }
+
+                (&name[..idx], Some(tag.to_string()))
+            }
+            _ => (name, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest)) if Self::is_registry_component(first) => {
+                (Self::normalize_registry(first), rest.to_string())
+            }
+            _ => (Self::DOCKER_HUB.to_string(), name.to_string()),
+        };
+
+        if !repository.split('/').all(Self::is_valid_path_component) {
+            return Err(invalid(reference, "malformed repository name"));
+        }
+
+        // The `library/` prefix is used for official images, and only makes
+        // sense for Docker Hub
+        let repository = if registry == Self::DOCKER_HUB && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository
+        };
+
+        // Default to `latest` only if we weren't pinned to a digest
+        let tag = if tag.is_none() && digest.is_none() {
+            Some(Self::DEFAULT_TAG.to_string())
+        } else {
+            tag
+        };
+
+        Ok(Self {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(reference: &str) -> Reference {
+        reference.parse().unwrap()
+    }
+
+    #[test]
+    fn docker_hub_defaults() {
+        assert_eq!(
+            parse("alpine"),
+            Reference {
+                registry: "docker.io".to_string(),
+                repository: "library/alpine".to_string(),
+                tag: Some("latest".to_string()),
+                digest: None,
+            }
+        );
+        assert_eq!(parse("org/app:1.2").repository, "org/app");
+        assert_eq!(parse("org/app:1.2").tag.as_deref(), Some("1.2"));
+    }
+
+    #[test]
+    fn docker_hub_aliases() {
+        for alias in [
+            "index.docker.io",
+            "registry-1.docker.io",
+            "registry.hub.docker.com",
+        ] {
+            let reference = parse(&format!("{alias}/alpine"));
+            assert!(reference.is_docker_hub());
+            assert_eq!(reference.repository, "library/alpine");
+        }
+
+        assert_eq!(
+            Reference::registry_api_url("index.docker.io"),
+            "https://registry-1.docker.io/v2"
+        );
+    }
+
+    #[test]
+    fn library_prefix_only_for_docker_hub() {
+        assert_eq!(parse("ghcr.io/alpine").repository, "alpine");
+        assert_eq!(parse("localhost/alpine").repository, "alpine");
+    }
+
+    #[test]
+    fn port_and_tag() {
+        let reference = parse("localhost:5000/app");
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "app");
+        assert_eq!(reference.tag.as_deref(), Some("latest"));
+
+        let reference = parse("localhost:5000/org/app:v1");
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "org/app");
+        assert_eq!(reference.tag.as_deref(), Some("v1"));
+
+        let reference = parse("[::1]:5000/app:v1");
+        assert_eq!(reference.registry, "[::1]:5000");
+        assert_eq!(reference.tag.as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn digest() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+
+        let reference = parse(&format!("ghcr.io/org/app@{digest}"));
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+        assert_eq!(reference.manifest_reference(), digest);
+
+        let reference = parse(&format!("ghcr.io/org/app:1.2@{digest}"));
+        assert_eq!(reference.tag.as_deref(), Some("1.2"));
+        assert_eq!(reference.manifest_reference(), digest);
+        assert_eq!(
+            reference.to_string(),
+            format!("ghcr.io/org/app:1.2@{digest}")
+        );
+    }
+
+    #[test]
+    fn insecure_registries() {
+        for registry in [
+            "localhost",
+            "localhost:5000",
+            "127.0.0.1",
+            "127.0.0.1:5000",
+            "[::1]",
+            "[::1]:5000",
+        ] {
+            assert!(Reference::is_insecure_registry(registry), "{registry}");
+        }
+
+        for registry in [
+            "docker.io",
+            "ghcr.io:443",
+            "[::2]:5000",
+            "localhost.example",
+        ] {
+            assert!(!Reference::is_insecure_registry(registry), "{registry}");
+        }
+
+        assert_eq!(
+            Reference::registry_api_url("[::1]:5000"),
+            "http://[::1]:5000/v2"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for reference in [
+            "",
+            "Alpine",
+            "alpine:",
+            "alpine@",
+            "alpine@sha256:xyz",
+            "alpine:1.2:3",
+            "org//app",
+            "ghcr.io/",
+        ] {
+            assert!(reference.parse::<Reference>().is_err(), "{reference}");
+        }
+    }
+}
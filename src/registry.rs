@@ -1,25 +1,36 @@
-use crate::util;
-use serde::Deserialize;
+use crate::{
+    auth::{AuthChallenge, Authorization, Token},
+    credentials::{self, Credentials},
+    digest::{self, VerifyingReader},
+    reference::Reference,
+    util,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Mutex;
 
 /// application/vnd.docker.container.image.v1+json
 /// application/vnd.docker.image.rootfs.diff.tar.gzip
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ManifestConfig {
-    #[serde(rename(deserialize = "mediaType"))]
+    #[serde(rename = "mediaType")]
     pub media_type: String,
-    pub size: i32,
+    pub size: u64,
     pub digest: String,
 }
 
 /// https://docs.docker.com/registry/spec/manifest-v2-2
 /// application/vnd.docker.distribution.manifest.v2+json
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Manifest {
-    #[serde(rename(deserialize = "schemaVersion"))]
+    #[serde(rename = "schemaVersion")]
     pub schema_version: i32,
-    #[serde(rename(deserialize = "mediaType"))]
+    /// Optional for OCI manifests, which are identified by the
+    /// `Content-Type` header or the descriptor pointing to them instead
+    #[serde(rename = "mediaType", default)]
     pub media_type: String,
-    config: ManifestConfig,
+    pub config: ManifestConfig,
     /// The first layer is the base image, and subsequent layers must be
     /// mounted on top of it
     pub layers: Vec<ManifestConfig>,
@@ -30,6 +41,21 @@ pub struct Manifest {
 pub struct ImagePlatform {
     pub architecture: String,
     pub os: String,
+    /// `v8` for `arm64`, `v7` for `arm`
+    pub variant: Option<String>,
+}
+
+/// linux/arm64/v8, as displayed by docker
+impl std::fmt::Display for ImagePlatform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Inner struct for list
@@ -37,7 +63,7 @@ pub struct ImagePlatform {
 pub struct ImageIndex {
     #[serde(rename(deserialize = "mediaType"))]
     pub media_type: String,
-    pub size: i32,
+    pub size: u64,
     pub digest: String,
     pub platform: ImagePlatform,
 }
@@ -96,7 +122,7 @@ pub struct ImageIndexV1 {
     "StopSignal": "SIGQUIT"
   }
 */
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ImageConfigRuntime {
     /// 8080/tcp, 8080/udp, 8080
@@ -108,179 +134,807 @@ pub struct ImageConfigRuntime {
     /// Binary to execute
     pub entrypoint: Option<Vec<String>>,
     pub stop_signal: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+}
+
+/// Used for root filesystems that don't come with an image config
+impl Default for ImageConfigRuntime {
+    fn default() -> Self {
+        Self {
+            exposed_ports: None,
+            env: vec![format!("PATH={}", util::DEFAULT_PATH)],
+            working_dir: None,
+            cmd: vec![String::from("/bin/sh")],
+            entrypoint: None,
+            stop_signal: None,
+            labels: None,
+        }
+    }
+}
+
+/// The step of the build that produced a layer
+#[derive(Debug, Deserialize)]
+pub struct ImageHistory {
+    pub created: Option<String>,
+    pub created_by: Option<String>,
+    pub comment: Option<String>,
+    /// Set for steps such as `ENV` that don't produce a layer
+    #[serde(default)]
+    pub empty_layer: bool,
 }
 
 /// application/vnd.oci.image.config.v1+json
 #[derive(Debug, Deserialize)]
 pub struct ImageConfig {
+    /// RFC 3339 timestamp
+    pub created: Option<String>,
     pub architecture: String,
     pub os: String,
     pub config: ImageConfigRuntime,
+    #[serde(default)]
+    pub history: Vec<ImageHistory>,
     // We don't care about the rest of the fields for now
 }
 
-pub struct RegistryClient {
-    image: String,
-    tag: String,
+/// application/json response of `/v2/<name>/tags/list`
+#[derive(Deserialize)]
+struct TagList {
+    /// Some registries send `null` for repositories without tags
+    tags: Option<Vec<String>>,
 }
 
-impl RegistryClient {
-    const AUTH_URL: &'static str = "https://auth.docker.io";
-    const AUTH_SERVICE: &'static str = "registry.docker.io";
+/// application/json response of `/v2/_catalog`
+#[derive(Deserialize)]
+struct Catalog {
+    repositories: Vec<String>,
+}
 
-    const REGISTRY_URL: &'static str = "https://registry-1.docker.io/v2";
+/// What a tag currently points to, see `RegistryClient::describe`
+#[derive(Debug)]
+pub struct TagInfo {
+    pub digest: String,
+    pub media_type: String,
+    /// linux/amd64, one for each image in multi-platform images
+    pub platforms: Vec<String>,
+}
+
+/// Extract the target of the `rel="next"` link from a `Link` header
+/// </v2/_catalog?last=b&n=100>; rel="next"
+fn next_link(header: &str) -> Option<&str> {
+    header.split(',').find_map(|link| {
+        let (url, params) = link.trim().strip_prefix('<')?.split_once('>')?;
+
+        params
+            .split(';')
+            .any(|param| matches!(param.trim(), "rel=\"next\"" | "rel=next"))
+            .then_some(url)
+    })
+}
+
+/// Send an unauthenticated request to the base endpoint, the registry
+/// either lets us through or tells us how to authenticate in the
+/// `WWW-Authenticate` header
+fn probe(agent: &ureq::Agent, api_url: &str) -> std::io::Result<AuthChallenge> {
+    match agent.get(format!("{api_url}/").as_str()).call() {
+        Ok(_) => Ok(AuthChallenge::Anonymous),
+        Err(ureq::Error::Status(401, response)) => match response.header("WWW-Authenticate") {
+            Some(header) => AuthChallenge::parse(header),
+            None => Err(std::io::Error::other(format!(
+                "{api_url} returned 401 without a WWW-Authenticate header"
+            ))),
+        },
+        Err(err) => Err(util::ureq_to_io(err)),
+    }
+}
 
-    const MANIFEST_V2: &'static str = "application/vnd.docker.distribution.manifest.v2+json";
-    const MANIFEST_LIST_V2: &'static str =
+/// Exchange for a token at the token server advertised in the challenge,
+/// authenticating with the credentials if we have any
+fn get_token(
+    agent: &ureq::Agent,
+    realm: &str,
+    service: Option<&str>,
+    scopes: &[String],
+    credentials: Option<&Credentials>,
+) -> std::io::Result<Token> {
+    let mut request = agent.get(realm);
+
+    if let Some(service) = service {
+        request = request.query("service", service);
+    }
+
+    for scope in scopes {
+        request = request.query("scope", scope);
+    }
+
+    if let Some(credentials) = credentials {
+        request = request.set(
+            "Authorization",
+            format!("Basic {}", credentials.encode()).as_str(),
+        );
+    }
+
+    Token::from_response(request.call().map_err(util::ureq_to_io)?.into_json()?)
+}
+
+/// Streaming body of a blob, see `RegistryClient::get_blob`
+pub type BlobReader = VerifyingReader<Box<dyn Read + Send + Sync + 'static>>;
+
+/// How a blob ended up in the registry, see `RegistryClient::push_blob`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobPush {
+    /// The registry already had it
+    Exists,
+    /// Linked from another repository without uploading it
+    Mounted,
+    Uploaded,
+}
+
+pub struct RegistryClient {
+    reference: Reference,
+    /// Base URL of the registry's API, `https://ghcr.io/v2`
+    api_url: String,
+    /// Shared across all requests so that connections are reused
+    agent: ureq::Agent,
+    /// Discovered lazily through the first request to the registry, and
+    /// replaced by the challenge of any request it turns out not to cover
+    challenge: Mutex<Option<AuthChallenge>>,
+    /// Credentials from the docker/podman auth config, if any
+    credentials: Option<Credentials>,
+    /// Requested along with tokens, `repository:library/alpine:pull`
+    scopes: Vec<String>,
+    /// Repository on the same registry that blobs can be mounted from
+    mount_from: Option<String>,
+    /// Reused until it expires or the registry rejects it
+    token: Mutex<Option<Token>>,
+}
+
+impl RegistryClient {
+    pub const MANIFEST_V2: &'static str = "application/vnd.docker.distribution.manifest.v2+json";
+    pub const MANIFEST_LIST_V2: &'static str =
         "application/vnd.docker.distribution.manifest.list.v2+json";
 
-    const IMAGE_INDEX_V1: &'static str = "application/vnd.oci.image.index.v1+json";
-    const MANIFEST_V1: &'static str = "application/vnd.oci.image.manifest.v1+json";
+    pub const IMAGE_INDEX_V1: &'static str = "application/vnd.oci.image.index.v1+json";
+    pub const MANIFEST_V1: &'static str = "application/vnd.oci.image.manifest.v1+json";
+
+    /// Blobs larger than this are uploaded in chunks of this size, smaller
+    /// ones in a single request
+    pub const UPLOAD_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
 
-    pub fn new(image: &str, tag: &str) -> Self {
+    pub fn new(reference: &Reference) -> Self {
         Self {
-            image: {
-                // The `library/` prefix is used for official images
-                if !image.contains('/') {
-                    format!("library/{image}")
-                } else {
-                    String::from(image)
-                }
+            api_url: reference.api_url(),
+            reference: reference.clone(),
+            agent: ureq::AgentBuilder::new().build(),
+            challenge: Mutex::new(None),
+            credentials: credentials::lookup(&reference.registry),
+            scopes: vec![format!("repository:{}:pull", reference.repository)],
+            mount_from: None,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Client that can also upload to the repository. Blobs are mounted
+    /// from `mount_from` instead of being uploaded if it's another
+    /// repository on the same registry
+    pub fn for_push(reference: &Reference, mount_from: Option<&Reference>) -> Self {
+        let mut client = Self::new(reference);
+        client.scopes = vec![format!("repository:{}:pull,push", reference.repository)];
+
+        if let Some(from) = mount_from.filter(|from| {
+            from.registry == reference.registry && from.repository != reference.repository
+        }) {
+            client
+                .scopes
+                .push(format!("repository:{}:pull", from.repository));
+            client.mount_from = Some(from.repository.clone());
+        }
+
+        client
+    }
+
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+
+    /// Client for listing the repositories of a registry, which requires a
+    /// registry wide scope rather than one for a repository
+    pub fn for_catalog(registry: &str) -> Self {
+        let mut client = Self::new(&Reference {
+            registry: Reference::normalize_registry(registry),
+            repository: String::new(),
+            tag: None,
+            digest: None,
+        });
+        client.scopes = vec![String::from("registry:catalog:*")];
+
+        client
+    }
+
+    /// Check that the registry accepts the credentials, without requesting
+    /// access to any specific repository
+    pub fn login(registry: &str, credentials: &Credentials) -> std::io::Result<()> {
+        let agent = ureq::AgentBuilder::new().build();
+        let api_url = Reference::registry_api_url(registry);
+
+        match probe(&agent, &api_url)? {
+            AuthChallenge::Anonymous => {
+                log::warn!("{registry} doesn't require authentication");
+            }
+            AuthChallenge::Basic { .. } => {
+                agent
+                    .get(format!("{api_url}/").as_str())
+                    .set(
+                        "Authorization",
+                        format!("Basic {}", credentials.encode()).as_str(),
+                    )
+                    .call()
+                    .map_err(util::ureq_to_io)?;
+            }
+            AuthChallenge::Bearer { realm, service, .. } => {
+                get_token(&agent, &realm, service.as_deref(), &[], Some(credentials))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn challenge(&self) -> std::io::Result<AuthChallenge> {
+        let mut cached = self.challenge.lock().expect("challenge lock poisoned");
+
+        if let Some(challenge) = cached.as_ref() {
+            return Ok(challenge.clone());
+        }
+
+        let challenge = probe(&self.agent, &self.api_url)?;
+        log::info!("Registry {} requested auth: {challenge:?}", self.api_url);
+
+        Ok(cached.insert(challenge).clone())
+    }
+
+    /// Registries can let the base endpoint through anonymously and still
+    /// require auth for some repositories, in which case the rejected
+    /// request carries the challenge to follow instead
+    fn update_challenge(&self, response: &ureq::Response) {
+        let Some(header) = response.header("WWW-Authenticate") else {
+            return;
+        };
+
+        match AuthChallenge::parse(header) {
+            Ok(challenge) => {
+                log::info!("Registry {} requested auth: {challenge:?}", self.api_url);
+                *self.challenge.lock().expect("challenge lock poisoned") = Some(challenge);
+            }
+            Err(err) => log::warn!("Ignoring challenge: {err}"),
+        }
+    }
+
+    /// Follow the flow requested by the registry's challenge
+    fn fetch_token(&self) -> std::io::Result<Token> {
+        match &self.challenge()? {
+            AuthChallenge::Anonymous => Ok(Token::new(Authorization::Anonymous)),
+            AuthChallenge::Basic { .. } => match &self.credentials {
+                Some(credentials) => Ok(Token::new(Authorization::Basic(credentials.encode()))),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!(
+                        "{} requires credentials, use `dabba login`",
+                        self.reference.registry
+                    ),
+                )),
             },
-            tag: String::from(tag),
+            AuthChallenge::Bearer { realm, service, .. } => {
+                log::info!("Fetching token from {realm}");
+
+                get_token(
+                    &self.agent,
+                    realm,
+                    service.as_deref(),
+                    &self.scopes,
+                    self.credentials.as_ref(),
+                )
+            }
+        }
+    }
+
+    /// Return the cached authorization, refreshing it if it has expired
+    /// The lock is held while fetching so that concurrent requests don't
+    /// all race to fetch a new token
+    fn authorization(&self) -> std::io::Result<Authorization> {
+        let mut token = self.token.lock().expect("token lock poisoned");
+
+        match token.as_ref() {
+            Some(cached) if !cached.is_expired() => Ok(cached.authorization.clone()),
+            _ => {
+                let fresh = self.fetch_token()?;
+                let authorization = fresh.authorization.clone();
+                *token = Some(fresh);
+
+                Ok(authorization)
+            }
+        }
+    }
+
+    fn invalidate_token(&self) {
+        *self.token.lock().expect("token lock poisoned") = None;
+    }
+
+    /// Perform an authorized request constructed by `build`, retrying once
+    /// with a fresh token if the registry rejects the cached one
+    fn call(
+        &self,
+        build: impl Fn(&ureq::Agent) -> ureq::Request,
+    ) -> std::io::Result<ureq::Response> {
+        self.call_with(build, None)
+    }
+
+    /// Same as `call`, with `body` as the request's body
+    fn send(
+        &self,
+        build: impl Fn(&ureq::Agent) -> ureq::Request,
+        body: &[u8],
+    ) -> std::io::Result<ureq::Response> {
+        self.call_with(build, Some(body))
+    }
+
+    fn call_with(
+        &self,
+        build: impl Fn(&ureq::Agent) -> ureq::Request,
+        body: Option<&[u8]>,
+    ) -> std::io::Result<ureq::Response> {
+        for attempt in 0..2 {
+            let mut request = build(&self.agent);
+
+            if let Some(header) = self.authorization()?.header() {
+                request = request.set("Authorization", header.as_str());
+            }
+
+            let result = match body {
+                Some(body) => request.send_bytes(body),
+                None => request.call(),
+            };
+
+            match result {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(401, response)) if attempt == 0 => {
+                    log::info!("Registry rejected our token, refreshing");
+                    self.update_challenge(&response);
+                    self.invalidate_token();
+                }
+                Err(err) => return Err(util::ureq_to_io(err)),
+            }
+        }
+
+        unreachable!("the second attempt always returns");
+    }
+
+    /// Start downloading a blob, the returned reader streams the body and
+    /// hashes it along the way, `verify()` must be called on it after it has
+    /// been read till the end
+    pub fn get_blob(&self, descriptor: &ManifestConfig) -> std::io::Result<BlobReader> {
+        let url = format!(
+            "{}/{}/blobs/{}",
+            self.api_url, self.reference.repository, descriptor.digest
+        );
+        let blob = self.call(|agent| agent.get(&url))?;
+
+        VerifyingReader::new(blob.into_reader(), &descriptor.digest, descriptor.size)
+    }
+
+    /// Download a blob starting from `offset`, for resuming an interrupted
+    /// download. Returns the body along with the offset it actually starts
+    /// at, as registries are free to ignore the `Range` header and send the
+    /// whole blob instead
+    pub fn get_blob_range(
+        &self,
+        descriptor: &ManifestConfig,
+        offset: u64,
+    ) -> std::io::Result<(Box<dyn Read + Send + Sync + 'static>, u64)> {
+        let url = format!(
+            "{}/{}/blobs/{}",
+            self.api_url, self.reference.repository, descriptor.digest
+        );
+        let blob = self.call(|agent| {
+            let request = agent.get(&url);
+
+            if offset > 0 {
+                request.set("Range", format!("bytes={offset}-").as_str())
+            } else {
+                request
+            }
+        })?;
+
+        if blob.status() != 206 {
+            return Ok((blob.into_reader(), 0));
+        }
+
+        // bytes 1000-4999/5000
+        let start = blob
+            .header("Content-Range")
+            .and_then(|range| range.strip_prefix("bytes "))
+            .and_then(|range| range.split('-').next())
+            .and_then(|start| start.parse::<u64>().ok());
+
+        match start {
+            Some(start) if start == offset => Ok((blob.into_reader(), start)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Unexpected Content-Range for {}: {:?}",
+                    descriptor.digest,
+                    blob.header("Content-Range")
+                ),
+            )),
         }
     }
 
-    fn get_token(&self) -> Result<String, ureq::Error> {
-        let mut json: serde_json::Value = ureq::get(
-            format!(
-                "{}/token?service={}&scope=repository:{}:pull",
-                Self::AUTH_URL,
-                Self::AUTH_SERVICE,
-                self.image
+    /// Check whether the repository already has a blob
+    pub fn has_blob(&self, digest: &str) -> std::io::Result<bool> {
+        let url = format!(
+            "{}/{}/blobs/{digest}",
+            self.api_url, self.reference.repository
+        );
+
+        match self.call(|agent| agent.head(&url)) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// URLs in `Location` and `Link` headers may be relative to the registry
+    fn absolute_url(&self, url: &str) -> String {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return url.to_string();
+        }
+
+        let origin = self.api_url.strip_suffix("/v2").unwrap_or(&self.api_url);
+
+        format!("{origin}{url}")
+    }
+
+    /// Upload sessions are identified by the `Location` of the previous
+    /// response
+    fn upload_location(&self, response: &ureq::Response) -> std::io::Result<String> {
+        let location = response.header("Location").ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} didn't return an upload location", response.get_url()),
             )
-            .as_str(),
-        )
-        .call()?
-        .into_json()?;
+        })?;
+
+        Ok(self.absolute_url(location))
+    }
+
+    /// Fetch every page of a paginated list, following the `Link` headers
+    /// https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-tags
+    fn paginate<T: serde::de::DeserializeOwned>(
+        &self,
+        url: String,
+        mut collect: impl FnMut(T),
+    ) -> std::io::Result<()> {
+        let mut next = Some(url);
+
+        while let Some(url) = next.take() {
+            let response = self.call(|agent| agent.get(&url))?;
 
-        if let serde_json::Value::String(token) = json["token"].take() {
-            return Ok(token);
+            next = response
+                .header("Link")
+                .and_then(next_link)
+                .map(|link| self.absolute_url(link));
+
+            collect(util::serde_deserialize_or_err(&response.into_string()?)?);
         }
 
-        panic!("Invalid token!");
+        Ok(())
     }
 
-    pub fn get_blob(&self, digest: &str) -> Result<Vec<u8>, ureq::Error> {
-        let blob =
-            ureq::get(format!("{}/{}/blobs/{}", Self::REGISTRY_URL, self.image, digest,).as_str())
-                .set(
-                    "Authorization",
-                    format!("Bearer {}", self.get_token()?).as_str(),
-                )
-                .call()?;
+    /// List all the tags of the repository
+    pub fn list_tags(&self) -> std::io::Result<Vec<String>> {
+        let mut tags = Vec::new();
+
+        self.paginate(
+            format!("{}/{}/tags/list", self.api_url, self.reference.repository),
+            |page: TagList| tags.extend(page.tags.unwrap_or_default()),
+        )?;
+
+        Ok(tags)
+    }
+
+    /// List all the repositories of the registry, which most public
+    /// registries don't allow, see `for_catalog`
+    pub fn catalog(&self) -> std::io::Result<Vec<String>> {
+        let mut repositories = Vec::new();
+
+        self.paginate(format!("{}/_catalog", self.api_url), |page: Catalog| {
+            repositories.extend(page.repositories)
+        })?;
+
+        Ok(repositories)
+    }
+
+    /// Find the digest of the manifest that a tag (or digest) points to,
+    /// along with the platforms it's available for
+    pub fn describe(&self, manifest_reference: &str) -> std::io::Result<TagInfo> {
+        let (manifest, content_type) = self.get_manifest_inner(false, manifest_reference)?;
+        let parsed: serde_json::Value = util::serde_deserialize_or_err(&manifest)?;
+
+        let media_type = Self::manifest_media_type(&parsed, content_type.as_deref())
+            .unwrap_or(Self::MANIFEST_V1)
+            .to_string();
 
-        if let Some(len) = blob.header("Content-Length") {
-            let mut bytes: Vec<u8> = Vec::with_capacity(len.parse().expect("failed to parse int"));
-            blob.into_reader().read_to_end(&mut bytes)?;
+        let platforms = match media_type.as_str() {
+            Self::MANIFEST_LIST_V2 | Self::IMAGE_INDEX_V1 => {
+                let manifest_list: ImageIndexV1 = util::serde_deserialize_or_err(&manifest)?;
 
-            return Ok(bytes);
+                manifest_list
+                    .manifests
+                    .iter()
+                    .map(|manifest_item| manifest_item.platform.to_string())
+                    .collect()
+            }
+            // Single platform images only record it in their config
+            _ => {
+                let (config, _) =
+                    self.get_image_config(&util::serde_deserialize_or_err(&manifest)?)?;
+
+                vec![format!("{}/{}", config.os, config.architecture)]
+            }
+        };
+
+        Ok(TagInfo {
+            digest: digest::sha256(manifest.as_bytes()),
+            media_type,
+            platforms,
+        })
+    }
+
+    /// Upload a blob unless the repository already has it, mounting it from
+    /// another repository if possible
+    /// https://github.com/opencontainers/distribution-spec/blob/main/spec.md#pushing-blobs
+    pub fn push_blob(
+        &self,
+        descriptor: &ManifestConfig,
+        mut blob: impl Read,
+    ) -> std::io::Result<BlobPush> {
+        if self.has_blob(&descriptor.digest)? {
+            return Ok(BlobPush::Exists);
+        }
+
+        let url = format!(
+            "{}/{}/blobs/uploads/",
+            self.api_url, self.reference.repository
+        );
+
+        // Registries that can't mount the blob start a regular upload instead
+        let response = self.send(
+            |agent| match &self.mount_from {
+                Some(from) => agent
+                    .post(&url)
+                    .query("mount", &descriptor.digest)
+                    .query("from", from),
+                None => agent.post(&url),
+            },
+            &[],
+        )?;
+
+        if response.status() == 201 {
+            return Ok(BlobPush::Mounted);
         }
 
-        panic!("No Content-Length!");
+        let mut location = self.upload_location(&response)?;
+        let mut offset = 0;
+        let mut chunk = Vec::new();
+
+        loop {
+            chunk.clear();
+            (&mut blob)
+                .take(Self::UPLOAD_CHUNK_SIZE)
+                .read_to_end(&mut chunk)?;
+
+            // The last chunk is sent along with the request closing the
+            // session, which is all there is to it for small blobs
+            if (chunk.len() as u64) < Self::UPLOAD_CHUNK_SIZE {
+                break;
+            }
+
+            let range = format!("{offset}-{}", offset + chunk.len() as u64 - 1);
+            let response = self.send(
+                |agent| {
+                    agent
+                        .patch(&location)
+                        .set("Content-Type", "application/octet-stream")
+                        .set("Content-Range", &range)
+                },
+                &chunk,
+            )?;
+
+            offset += chunk.len() as u64;
+            location = self.upload_location(&response)?;
+        }
+
+        if offset + chunk.len() as u64 != descriptor.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} is {} bytes, expected {}",
+                    descriptor.digest,
+                    offset + chunk.len() as u64,
+                    descriptor.size
+                ),
+            ));
+        }
+
+        self.send(
+            |agent| {
+                agent
+                    .put(&location)
+                    .query("digest", &descriptor.digest)
+                    .set("Content-Type", "application/octet-stream")
+            },
+            &chunk,
+        )?;
+
+        Ok(BlobPush::Uploaded)
+    }
+
+    /// Upload a manifest under the reference's tag (or digest), all the
+    /// blobs it refers to must have been pushed already. Returns it's digest
+    pub fn put_manifest(&self, manifest: &str, media_type: &str) -> std::io::Result<String> {
+        let digest = digest::sha256(manifest.as_bytes());
+        let url = format!(
+            "{}/{}/manifests/{}",
+            self.api_url,
+            self.reference.repository,
+            self.reference.manifest_reference()
+        );
+
+        let response = self.send(
+            |agent| agent.put(&url).set("Content-Type", media_type),
+            manifest.as_bytes(),
+        )?;
+
+        match response.header("Docker-Content-Digest") {
+            Some(returned) if returned != digest => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Registry stored the manifest as {returned}, expected {digest}"),
+            )),
+            _ => Ok(digest),
+        }
     }
 
+    /// `manifest_reference` is either a tag or a digest
     fn get_manifest_inner(
         &self,
         enable_oci: bool,
-        digest: Option<&str>,
-    ) -> Result<String, ureq::Error> {
+        manifest_reference: &str,
+    ) -> std::io::Result<(String, Option<String>)> {
         // /library/alpine/manifests/latest
         // /library/ubuntu/manifests/sha256:b492494d8e0113c4ad3fe4528a4b5ff89faa5331f7d52c5c138196f69ce176a6
-        let mut request = ureq::get(
-            format!(
-                "{}/{}/manifests/{}",
-                Self::REGISTRY_URL,
-                &self.image,
-                digest.unwrap_or(&self.tag),
-            )
-            .as_str(),
-        )
-        .set(
-            "Authorization",
-            format!("Bearer {}", self.get_token()?).as_str(),
-        )
-        .set("Accept", Self::MANIFEST_V2)
-        .set("Accept", Self::MANIFEST_LIST_V2);
-
-        // For some reason, Docker's registry starts sending us legacy DOCKER
-        // V2.1 manifests https://docs.docker.com/registry/spec/manifest-v2-1/
-        // When we request OCI-compliant ones, so we just special case this
-        // for when we know we will get OCI-compliant responses
-        request = if enable_oci {
-            request
-                .set("Accept", Self::IMAGE_INDEX_V1)
-                .set("Accept", Self::MANIFEST_V1)
-        } else {
-            request
-        };
+        let url = format!(
+            "{}/{}/manifests/{manifest_reference}",
+            self.api_url, &self.reference.repository,
+        );
+
+        let response = self.call(|agent| {
+            let request = agent
+                .get(&url)
+                .set("Accept", Self::MANIFEST_V2)
+                .set("Accept", Self::MANIFEST_LIST_V2);
+
+            // For some reason, Docker's registry starts sending us legacy DOCKER
+            // V2.1 manifests https://docs.docker.com/registry/spec/manifest-v2-1/
+            // When we request OCI-compliant ones, so we just special case this
+            // for when we know we will get OCI-compliant responses
+            if enable_oci {
+                request
+                    .set("Accept", Self::IMAGE_INDEX_V1)
+                    .set("Accept", Self::MANIFEST_V1)
+            } else {
+                request
+            }
+        })?;
+
+        // Parameters such as the charset don't matter to us
+        let content_type = response.header("Content-Type").map(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        });
+        let manifest = response.into_string()?;
+
+        // Content fetched by digest must match it, tags are mutable so
+        // there's nothing to compare against. Tags can't contain ':'
+        if manifest_reference.contains(':') {
+            digest::verify_bytes(manifest.as_bytes(), manifest_reference)?;
+        }
 
-        Ok(request.call()?.into_string()?)
+        Ok((manifest, content_type))
     }
 
-    pub fn get_manifest(&self) -> Result<Manifest, ureq::Error> {
-        let manifest = self.get_manifest_inner(false, None)?;
+    /// OCI manifests may leave out `mediaType`, the `Content-Type` header
+    /// the registry served them with identifies them in that case
+    fn manifest_media_type<'a>(
+        parsed: &'a serde_json::Value,
+        content_type: Option<&'a str>,
+    ) -> Option<&'a str> {
+        parsed["mediaType"].as_str().or(content_type)
+    }
+
+    /// Returns the manifest for our platform, along with the raw JSON it was
+    /// parsed from as that's what the manifest's digest covers
+    pub fn get_manifest(&self) -> std::io::Result<(Manifest, String)> {
+        let (manifest, content_type) =
+            self.get_manifest_inner(false, self.reference.manifest_reference())?;
         let parsed: serde_json::Value = util::serde_deserialize_or_err(&manifest)?;
 
-        if let serde_json::Value::String(token) = &parsed["mediaType"] {
-            match token.as_str() {
-                Self::MANIFEST_V2 => {
-                    return Ok(util::serde_deserialize_or_err(&manifest)?);
-                }
-                Self::MANIFEST_LIST_V2 | Self::IMAGE_INDEX_V1 => {
-                    let manifest_list: ImageIndexV1 = util::serde_deserialize_or_err(&manifest)?;
-                    let manifests: Vec<&ImageIndex> = manifest_list
-                        .manifests
-                        .iter()
-                        .filter(|manifest_item| {
-                            util::is_compatible_arch(&manifest_item.platform.architecture)
-                                && manifest_item.platform.os == "linux"
-                        })
-                        .collect();
-
-                    // https://github.com/moby/moby/issues/45077
-                    // https://github.com/moby/moby/issues/43126#issuecomment-1406280316
-                    // We must fetch the manifest again, specifying the digest in the request
-                    // $ docker buildx imagetools inspect ubuntu:latest
-                    // Name:      docker.io/library/ubuntu:latest
-                    // MediaType: application/vnd.oci.image.index.v1+json
-                    // Digest:    sha256:aabed3296a3d45cede1dc866a24476c4d7e093aa806263c27ddaadbdce3c1054
-                    //
-                    // Manifests:
-                    //   Name:      docker.io/library/ubuntu:latest@sha256:b492494d8e0113c4ad3fe4528a4b5ff89faa5331f7d52c5c138196f69ce176a6
-                    //   MediaType: application/vnd.oci.image.manifest.v1+json
-                    //   Platform:  linux/amd64
-                    if let Some(final_manifest) = manifests.first() {
-                        return Ok(util::serde_deserialize_or_err(&self.get_manifest_inner(
-                            true,
-                            Some(final_manifest.digest.as_str()),
-                        )?)?);
-                    }
-
-                    panic!("No relevant manifests found! {parsed:#?}");
-                }
-                media_type => {
-                    panic!("Got invalid media type {media_type}: {parsed:#?}");
-                }
+        let invalid = |reason: String| std::io::Error::new(std::io::ErrorKind::InvalidData, reason);
+
+        match Self::manifest_media_type(&parsed, content_type.as_deref()) {
+            // Registries may still send OCI manifests for single
+            // platform images, such as ones we pushed ourselves
+            Some(Self::MANIFEST_V2 | Self::MANIFEST_V1) => {
+                Ok((util::serde_deserialize_or_err(&manifest)?, manifest))
             }
-        }
+            Some(Self::MANIFEST_LIST_V2 | Self::IMAGE_INDEX_V1) => {
+                let manifest_list: ImageIndexV1 = util::serde_deserialize_or_err(&manifest)?;
+                let manifests: Vec<&ImageIndex> = manifest_list
+                    .manifests
+                    .iter()
+                    .filter(|manifest_item| {
+                        util::is_compatible_arch(&manifest_item.platform.architecture)
+                            && manifest_item.platform.os == "linux"
+                    })
+                    .collect();
+
+                // https://github.com/moby/moby/issues/45077
+                // https://github.com/moby/moby/issues/43126#issuecomment-1406280316
+                // We must fetch the manifest again, specifying the digest in the request
+                // $ docker buildx imagetools inspect ubuntu:latest
+                // Name:      docker.io/library/ubuntu:latest
+                // MediaType: application/vnd.oci.image.index.v1+json
+                // Digest:    sha256:aabed3296a3d45cede1dc866a24476c4d7e093aa806263c27ddaadbdce3c1054
+                //
+                // Manifests:
+                //   Name:      docker.io/library/ubuntu:latest@sha256:b492494d8e0113c4ad3fe4528a4b5ff89faa5331f7d52c5c138196f69ce176a6
+                //   MediaType: application/vnd.oci.image.manifest.v1+json
+                //   Platform:  linux/amd64
+                let final_manifest = manifests.first().ok_or_else(|| {
+                    invalid(format!(
+                        "No manifest of {} is for linux/{}",
+                        self.reference,
+                        std::env::consts::ARCH
+                    ))
+                })?;
+                let (manifest, _) = self.get_manifest_inner(true, &final_manifest.digest)?;
 
-        panic!("Invalid manifest: {manifest}");
+                Ok((util::serde_deserialize_or_err(&manifest)?, manifest))
+            }
+            Some(media_type) => Err(invalid(format!(
+                "Manifest of {} has unsupported media type {media_type}",
+                self.reference
+            ))),
+            None => Err(invalid(format!(
+                "Manifest of {} has no media type",
+                self.reference
+            ))),
+        }
     }
 
-    /// Parses and return's the image config for running the container
-    pub fn get_image_config(&self, manifest: &Manifest) -> Result<ImageConfig, ureq::Error> {
-        let config = self.get_blob(&manifest.config.digest)?;
-        Ok(util::serde_result_to_ureq(serde_json::from_slice(&config))?)
+    /// Parses and return's the image config for running the container,
+    /// along with the raw JSON
+    pub fn get_image_config(&self, manifest: &Manifest) -> std::io::Result<(ImageConfig, Vec<u8>)> {
+        let mut reader = self.get_blob(&manifest.config)?;
+
+        let mut config = Vec::new();
+        reader.read_to_end(&mut config)?;
+        reader.verify()?;
+
+        Ok((
+            util::serde_result_to_ureq(serde_json::from_slice(&config))?,
+            config,
+        ))
     }
 }
@@ -0,0 +1,131 @@
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Directories that dabba keeps it's state in
+#[derive(Clone, Debug)]
+pub struct Root {
+    /// Persistent data such as the image store
+    pub data: PathBuf,
+    /// State that doesn't need to survive a reboot, sockets and mountpoints
+    pub runtime: PathBuf,
+}
+
+impl Root {
+    /// Overrides the XDG defaults, same as `--root`
+    pub const ENV: &'static str = "DABBA_ROOT";
+
+    /// Keep everything under a single directory
+    pub fn new(root: &Path) -> Self {
+        Self {
+            data: root.to_path_buf(),
+            runtime: root.join("run"),
+        }
+    }
+
+    /// Use `$DABBA_ROOT` if it's set, otherwise follow the XDG base
+    /// directory spec, `$XDG_DATA_HOME/dabba` and `$XDG_RUNTIME_DIR/dabba`
+    pub fn from_env() -> Self {
+        if let Some(root) = std::env::var_os(Self::ENV).filter(|root| !root.is_empty()) {
+            return Self::new(Path::new(&root));
+        }
+
+        let uid = nix::unistd::getuid();
+
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })
+            .map(|dir| dir.join("dabba"))
+            .unwrap_or_else(|| std::env::temp_dir().join(format!("dabba-{uid}")));
+
+        // Not cleared on reboot if we fall back to /tmp, but that's only
+        // used for sockets and mountpoints which are recreated anyway
+        let runtime = std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| PathBuf::from(dir).join("dabba"))
+            .unwrap_or_else(|| std::env::temp_dir().join(format!("dabba-{uid}/run")));
+
+        Self { data, runtime }
+    }
+
+    /// Create the directories, only accessible by us as the runtime
+    /// directory may be under a world-writable /tmp
+    /// Directories that already exist are only used if they're private to
+    /// us, as the names under /tmp are predictable and anyone could have
+    /// created them first
+    pub fn create(&self) -> Result<(), std::io::Error> {
+        for dir in [&self.data, &self.runtime] {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+
+            Self::check_private(dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// `dir` must be a directory owned by us that nobody else can access,
+    /// and nobody but us and root may be able to replace it or any of it's
+    /// parents, sticky directories such as /tmp only let us replace our own
+    fn check_private(dir: &Path) -> Result<(), std::io::Error> {
+        let uid = nix::unistd::getuid().as_raw();
+
+        let refuse = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Refusing to use {dir:?}, {reason}"),
+            )
+        };
+
+        let metadata = std::fs::symlink_metadata(dir)?;
+
+        if !metadata.is_dir() {
+            return Err(refuse("it's not a directory"));
+        }
+
+        if metadata.uid() != uid {
+            return Err(refuse(&format!("it's owned by uid {}", metadata.uid())));
+        }
+
+        if metadata.mode() & 0o077 != 0 {
+            return Err(refuse("it's accessible to other users"));
+        }
+
+        for parent in std::fs::canonicalize(dir)?.ancestors().skip(1) {
+            let metadata = std::fs::metadata(parent)?;
+            let shared = metadata.mode() & 0o022 != 0 && metadata.mode() & 0o1000 == 0;
+
+            if (metadata.uid() != uid && metadata.uid() != 0) || shared {
+                return Err(refuse(&format!("other users can modify {parent:?}")));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Image store, see `Storage`
+    pub fn storage_dir(&self) -> PathBuf {
+        self.data.join("storage")
+    }
+
+    /// Mountpoint for the container's root filesystem, a tmpfs is mounted
+    /// over it inside the container's mount namespace
+    pub fn overlay_dir(&self) -> PathBuf {
+        self.runtime.join("dabba-overlay")
+    }
+
+    /// Root filesystem tarballs passed to `run --rootfs` are extracted here,
+    /// see `Rootfs`
+    pub fn rootfs_dir(&self) -> PathBuf {
+        self.runtime.join("rootfs")
+    }
+
+    /// State of each container, see `Container`
+    pub fn containers_dir(&self) -> PathBuf {
+        self.runtime.join("containers")
+    }
+}
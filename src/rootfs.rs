@@ -0,0 +1,53 @@
+use crate::{layer, root::Root, util};
+use std::path::{Path, PathBuf};
+
+/// Root filesystem of a container that isn't backed by an image, such as
+/// the output of debootstrap or an alpine minirootfs tarball
+pub enum Rootfs {
+    /// Used as is, it's never written to as it becomes the lower layer
+    Dir(PathBuf),
+    /// Extracted from a tarball, removed when dropped
+    Extracted(PathBuf),
+}
+
+impl Rootfs {
+    /// Directories are used in place, tarballs (optionally compressed) are
+    /// extracted under `root` for the lifetime of the container
+    pub fn open(root: &Root, path: &Path) -> Result<Self, std::io::Error> {
+        // Fails early if the path doesn't exist, and keeps the lowerdir we
+        // hand to OverlayFS independent of the working directory at mount time
+        let path = path.canonicalize()?;
+
+        if path.is_dir() {
+            return Ok(Self::Dir(path));
+        }
+
+        let dest = root.rootfs_dir().join(std::process::id().to_string());
+        util::remove_tree(&dest)?;
+
+        log::info!("Extracting {path:?} to {dest:?}");
+
+        // Constructed before extracting so that a partial extraction is
+        // cleaned up too
+        let rootfs = Self::Extracted(dest);
+        layer::unpack_compressed(std::fs::File::open(&path)?, None, rootfs.path())?;
+
+        Ok(rootfs)
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Dir(path) | Self::Extracted(path) => path,
+        }
+    }
+}
+
+impl Drop for Rootfs {
+    fn drop(&mut self) {
+        if let Self::Extracted(path) = self {
+            if let Err(err) = util::remove_tree(path) {
+                log::warn!("Failed to remove {path:?}: {err}");
+            }
+        }
+    }
+}
@@ -1,21 +1,186 @@
+#[cfg(feature = "wip")]
+use crate::cgroups::{CGroup, CGroupConfig};
 use crate::{
+    container::Container,
     idmap_helper,
     ipc::{ChildEvent, Ipc, ParentEvent},
     mount_helper,
     mount_helper::MountType,
+    pty::Pty,
     registry::ImageConfigRuntime,
-    slirp::{PortMapping, SlirpHelper},
+    root::Root,
+    slirp::SlirpHelper,
     util,
 };
+use nix::errno::Errno;
 use nix::sched::CloneFlags;
-use nix::sys::signal::Signal;
-use nix::unistd::Pid;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::SignalFd;
+use nix::unistd::{ForkResult, Pid};
+use std::convert::Infallible;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::time::Duration;
+
+/// How the container's standard streams are connected to ours
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdioConfig {
+    /// Allocate a terminal for the container, `-t`
+    pub tty: bool,
+    /// Keep stdin attached, it's /dev/null otherwise, `-i`
+    pub interactive: bool,
+}
+
+/// Signals asking us to exit, translated to the container's stop signal
+const STOP_SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// Everything else is passed on to the container as is, including
+/// real-time signals. SIGCHLD is about our own children, SIGALRM times the
+/// grace period, SIGPIPE and SIGURG are raised by our own I/O like docker
+/// assumes, and faults must kill us as returning from the handler would
+/// just fault again. Job control signals keep their default action so ^Z
+/// suspends us, rather than stopping the command with no way to resume it
+/// from this terminal. SIGKILL and SIGSTOP can't be caught
+const NOT_FORWARDED: [Signal; 15] = [
+    Signal::SIGCHLD,
+    Signal::SIGALRM,
+    Signal::SIGPIPE,
+    Signal::SIGURG,
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGFPE,
+    Signal::SIGILL,
+    Signal::SIGTRAP,
+    Signal::SIGSYS,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+    Signal::SIGKILL,
+    Signal::SIGSTOP,
+];
+
+/// State of the handler installed by `ForwardSignals`, a signal handler
+/// can't be passed anything else. There's only one sandbox per process
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(libc::SIGTERM);
+static STOP_TIMEOUT: AtomicU32 = AtomicU32::new(0);
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = FORWARD_PID.load(Ordering::SeqCst);
+
+    if pid <= 0 {
+        return;
+    }
+
+    let signal = match Signal::try_from(signal) {
+        Ok(signal) if STOP_SIGNALS.contains(&signal) => {
+            let timeout = STOP_TIMEOUT.load(Ordering::SeqCst);
+
+            if timeout == 0 {
+                libc::SIGKILL
+            } else {
+                // Only the first request starts the grace period
+                if !STOPPING.swap(true, Ordering::SeqCst) {
+                    nix::unistd::alarm::set(timeout);
+                }
+
+                STOP_SIGNAL.load(Ordering::SeqCst)
+            }
+        }
+        // The grace period is over, unless the alarm isn't ours
+        Ok(Signal::SIGALRM) if STOPPING.load(Ordering::SeqCst) => libc::SIGKILL,
+        Ok(Signal::SIGALRM) => return,
+        _ => signal,
+    };
+
+    // kill() is async-signal-safe, and there's nothing to do on failure
+    unsafe { libc::kill(pid, signal) };
+}
+
+/// Forwards the signals we receive to the container's init while it's
+/// installed, which passes them on to the command. Requests to exit are
+/// translated to the container's stop signal, and escalated to SIGKILL if
+/// it's still running after the stop timeout
+/// SIGWINCH is only forwarded along with a pty, without one the command
+/// has no terminal whose size could have changed
+struct ForwardSignals {
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl ForwardSignals {
+    fn install(
+        pid: Pid,
+        stop_signal: Signal,
+        stop_timeout: Duration,
+        tty: bool,
+    ) -> nix::Result<Self> {
+        FORWARD_PID.store(pid.as_raw(), Ordering::SeqCst);
+        STOP_SIGNAL.store(stop_signal as i32, Ordering::SeqCst);
+        STOP_TIMEOUT.store(
+            stop_timeout.as_secs().try_into().unwrap_or(u32::MAX),
+            Ordering::SeqCst,
+        );
+        STOPPING.store(false, Ordering::SeqCst);
+
+        // XXX nix's `sigaction` only takes a `Signal`, which real-time
+        // signals aren't
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Restart interrupted syscalls, waitpid() in particular
+        action.sa_flags = libc::SA_RESTART;
+
+        let mut guard = Self {
+            previous: Vec::new(),
+        };
+
+        let signals = Signal::iterator()
+            .filter(|signal| !NOT_FORWARDED.contains(signal))
+            .filter(|signal| tty || *signal != Signal::SIGWINCH)
+            .map(|signal| signal as libc::c_int)
+            .chain(libc::SIGRTMIN()..=libc::SIGRTMAX())
+            .chain([libc::SIGALRM]);
+
+        for signal in signals {
+            let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+            Errno::result(unsafe { libc::sigaction(signal, &action, &mut previous) })?;
+            guard.previous.push((signal, previous));
+        }
+
+        Ok(guard)
+    }
+}
+
+impl Drop for ForwardSignals {
+    fn drop(&mut self) {
+        nix::unistd::alarm::cancel();
+        FORWARD_PID.store(0, Ordering::SeqCst);
+
+        for (signal, previous) in &self.previous {
+            if unsafe { libc::sigaction(*signal, previous, std::ptr::null_mut()) } == -1 {
+                log::warn!(
+                    "Failed to restore the handler for signal {signal}: {}",
+                    Errno::last()
+                );
+            }
+        }
+    }
+}
 
 pub struct Sandbox {
     pub pid: Pid,
     /// Just store a binding for cleanup
     _slirp: SlirpHelper,
+    /// Relayed to our terminal when waiting for the sandbox
+    pty: Option<Pty>,
+    stdio: StdioConfig,
+    /// Exit code, once it has been waited for
+    exit_code: Option<i32>,
+    /// Sent when we're asked to exit, see `ForwardSignals`
+    stop_signal: Signal,
+    stop_timeout: Duration,
 }
 
 impl Sandbox {
@@ -50,10 +215,7 @@ impl Sandbox {
     }
 
     /// Perform the mounting dance
-    fn mount_and_pivot(layers: &[PathBuf]) -> Result<(), std::io::Error> {
-        let overlay_dir = format!("{}/dabba-overlay", util::get_base_path());
-        let target = Path::new(&overlay_dir);
-
+    fn mount_and_pivot(layers: &[PathBuf], target: &Path) -> Result<(), std::io::Error> {
         log::info!("Using {target:?} inside sandbox as overlay path");
 
         std::fs::create_dir_all(target)?;
@@ -94,7 +256,7 @@ impl Sandbox {
         nix::unistd::setsid()
     }
 
-    fn setup_child_inner(layers: &[PathBuf]) -> Result<(), std::io::Error> {
+    fn setup_child_inner(layers: &[PathBuf], overlay_dir: &Path) -> Result<(), std::io::Error> {
         log::info!("Spawned sandbox!");
 
         log::info!("Ensuring that child dies with parent");
@@ -104,7 +266,7 @@ impl Sandbox {
         Self::hostname()?;
 
         log::info!("Performing the mounting dance");
-        Self::mount_and_pivot(layers)?;
+        Self::mount_and_pivot(layers, overlay_dir)?;
 
         log::info!("Setting up new session");
         Self::new_session()?;
@@ -115,7 +277,34 @@ impl Sandbox {
         Ok(())
     }
 
-    fn setup_child(ipc: &Ipc, layers: &[PathBuf]) -> isize {
+    /// Connect the standard streams once the parent is done with it's setup
+    fn setup_stdio(ipc: &Ipc, stdio: StdioConfig) -> Result<(), std::io::Error> {
+        let pty = if stdio.tty {
+            match ipc.recv_in_child()? {
+                ParentEvent::PtyAllocated => Some(ipc.recv_pty_in_child()?),
+                _ => return Err(std::io::Error::other("parent failed to allocate a pty")),
+            }
+        } else {
+            None
+        };
+
+        Self::connect_stdio(pty, stdio)
+    }
+
+    /// Attach to the terminal at `pty` if one was allocated, otherwise
+    /// replace stdin with /dev/null unless it's interactive
+    fn connect_stdio(pty: Option<u32>, stdio: StdioConfig) -> Result<(), std::io::Error> {
+        if let Some(index) = pty {
+            Pty::attach(index)?;
+        } else if !stdio.interactive {
+            let null = std::fs::File::open("/dev/null")?;
+            nix::unistd::dup2(null.as_raw_fd(), libc::STDIN_FILENO)?;
+        }
+
+        Ok(())
+    }
+
+    fn setup_child(ipc: &Ipc, layers: &[PathBuf], overlay_dir: &Path, stdio: StdioConfig) -> isize {
         match ipc.recv_in_child().expect("failed to recv from parent!") {
             ParentEvent::CGroupFailure => {
                 log::warn!("Parent reported failure in CGroup setup, exiting");
@@ -132,9 +321,12 @@ impl Sandbox {
             ParentEvent::InitSuccess => {
                 log::info!("Received success event from parent, continuing setup")
             }
+            ParentEvent::PtyAllocated | ParentEvent::PtyFailure => {
+                unreachable!("pty is only allocated after init")
+            }
         }
 
-        if let Err(err) = Self::setup_child_inner(layers) {
+        if let Err(err) = Self::setup_child_inner(layers, overlay_dir) {
             log::error!("Failed to setup sandbox: {err}");
 
             ipc.send_from_child(ChildEvent::InitFailed)
@@ -146,6 +338,11 @@ impl Sandbox {
         ipc.send_from_child(ChildEvent::InitSuccess)
             .expect("failed to send from child!");
 
+        if let Err(err) = Self::setup_stdio(ipc, stdio) {
+            log::error!("Failed to setup stdio: {err}");
+            return 1;
+        }
+
         // TODO confirm if this can lead to a (harmless) double-close of the
         // IPC pipes in the sandbox process
         log::info!("Closing FDs");
@@ -154,7 +351,8 @@ impl Sandbox {
         0
     }
 
-    fn exec_with_config(config: &ImageConfigRuntime, env: &[String]) -> isize {
+    /// Replace our environment with the one the container's commands run in
+    fn setup_env(config: &ImageConfigRuntime, env: &[String]) {
         // Clear all environment variables
         util::clear_env();
 
@@ -162,20 +360,50 @@ impl Sandbox {
         util::set_env(&[
             "HOME=/root".to_string(),
             "TERM=xterm".to_string(),
-            "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string(),
+            format!("PATH={}", util::DEFAULT_PATH),
         ]);
 
-        // Set the user provided variables
-        util::set_env(env);
-
         // Set the variables specified in the image config
         util::set_env(&config.env);
 
-        if let Some(working_dir) = &config.working_dir {
-            if !working_dir.is_empty() {
-                std::env::set_current_dir(working_dir).expect("failed to set cwd!");
-            }
+        // Set the user provided variables, overriding the image's
+        util::set_env(env);
+    }
+
+    fn enter_working_dir(working_dir: Option<&str>) -> Result<(), std::io::Error> {
+        match working_dir {
+            Some(working_dir) if !working_dir.is_empty() => std::env::set_current_dir(working_dir),
+            _ => Ok(()),
+        }
+    }
+
+    /// Same convention as shells for processes killed by a signal
+    /// Same as `nix::sys::wait::waitpid`, but returns the raw status as
+    /// nix can't represent deaths by real-time signals. `None` if `WNOHANG`
+    /// was passed and nothing exited
+    fn waitpid(pid: Option<Pid>, flags: libc::c_int) -> Result<Option<(Pid, i32)>, Errno> {
+        let mut status = 0;
+
+        match unsafe { libc::waitpid(pid.map_or(-1, Pid::as_raw), &mut status, flags) } {
+            -1 => Err(Errno::last()),
+            0 => Ok(None),
+            pid => Ok(Some((Pid::from_raw(pid), status))),
+        }
+    }
+
+    /// Only for statuses of exited processes, `waitpid()` is never passed
+    /// `WUNTRACED` or `WCONTINUED`
+    fn exit_code(status: i32) -> i32 {
+        if libc::WIFSIGNALED(status) {
+            128 + libc::WTERMSIG(status)
+        } else {
+            libc::WEXITSTATUS(status)
         }
+    }
+
+    fn exec_with_config(config: &ImageConfigRuntime, env: &[String]) -> isize {
+        Self::setup_env(config, env);
+        Self::enter_working_dir(config.working_dir.as_deref()).expect("failed to set cwd!");
 
         let mut cmd = config.cmd.iter();
 
@@ -197,14 +425,82 @@ impl Sandbox {
 
         log::info!("Launching program '{argv0}' with args: {args:?}");
 
-        let status = std::process::Command::new(argv0)
-            .args(args)
-            .status()
-            .expect("failed to get status of launched command!");
+        // Block signals before spawning so that none are lost in between
+        let signals = SigSet::all();
+        signals.thread_block().expect("failed to block signals!");
 
-        log::info!("Process exited with status: {status:?}");
+        let mut command = std::process::Command::new(argv0);
+        command.args(args);
 
-        0
+        // The mask is inherited across exec, the command must start with
+        // none blocked
+        unsafe {
+            command.pre_exec(|| Ok(SigSet::empty().thread_set_mask()?));
+        }
+
+        // Reaped by `supervise` along with any orphans, waiting on it here
+        // would race with that
+        #[allow(clippy::zombie_processes)]
+        let child = command.spawn().expect("failed to launch command!");
+
+        let exit_code = Self::supervise(
+            Pid::from_raw(child.id() as i32),
+            SignalFd::new(&signals).expect("failed to create signalfd!"),
+        )
+        .expect("failed to supervise command!");
+
+        log::info!("Process exited with {exit_code}");
+
+        exit_code as isize
+    }
+
+    /// Act as the container's init until `child` exits, forwarding signals
+    /// to it and reaping any orphans that get reparented to us
+    /// Signals from outside the namespace would otherwise be dropped, as
+    /// init gets no default actions
+    fn supervise(child: Pid, mut signals: SignalFd) -> Result<i32, std::io::Error> {
+        loop {
+            let Some(info) = signals.read_signal()? else {
+                continue;
+            };
+
+            // Real-time signals have no `Signal`, so the raw number is used
+            let signo = info.ssi_signo as i32;
+
+            if signo == libc::SIGCHLD {
+                loop {
+                    match Self::waitpid(None, libc::WNOHANG) {
+                        Ok(None) | Err(Errno::ECHILD) => break,
+                        Ok(Some((pid, status))) if pid == child => {
+                            return Ok(Self::exit_code(status))
+                        }
+                        Ok(Some((pid, status))) => {
+                            log::info!("Reaped orphan {pid}: {}", Self::exit_code(status))
+                        }
+                        Err(Errno::EINTR) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+
+                continue;
+            }
+
+            // Sent by the kernel to the whole foreground process group, such
+            // as ^C on the terminal, which already includes the child
+            if info.ssi_code == libc::SI_KERNEL {
+                continue;
+            }
+
+            log::info!("Forwarding signal {signo} to {child}");
+
+            // We're init, the container goes down with us if we fail here
+            if unsafe { libc::kill(child.as_raw(), signo) } == -1 {
+                match Errno::last() {
+                    Errno::ESRCH => {}
+                    err => log::warn!("Failed to forward signal {signo} to {child}: {err}"),
+                }
+            }
+        }
     }
 
     /// Set up the namespace
@@ -215,21 +511,28 @@ impl Sandbox {
     /// > process will have the process ID 1 and will assume the role of
     /// > init(1) in the new namespace.
     pub fn spawn(
+        root: &Root,
+        container: &Container,
         layers: &[PathBuf],
-        config: &ImageConfigRuntime,
-        ports: &[PortMapping],
-        env: &[String],
+        stdio: StdioConfig,
     ) -> Result<Self, std::io::Error> {
+        let config = &container.state.config;
+        let env = &container.state.env;
+
+        // Rather than finding out only when we have to stop it
+        let stop_signal = container.stop_signal()?;
+
         // This doesn't need to be `static` as the cloned process
         // will get another copy of the stack, the memory is not shared
         // with the parent..
         let mut stack = [0_u8; 1024 * 1024];
 
         let ipc = Ipc::new()?;
+        let overlay_dir = root.overlay_dir();
 
         #[cfg(feature = "wip")]
         let mut cgroup = CGroup::new(
-            base_cgroup,
+            &CGroup::current()?,
             CGroupConfig {
                 mem: String::from("500m"),
             },
@@ -240,7 +543,7 @@ impl Sandbox {
         let pid = unsafe {
             nix::sched::clone(
                 Box::new(|| {
-                    let status = Self::setup_child(&ipc, layers);
+                    let status = Self::setup_child(&ipc, layers, &overlay_dir, stdio);
 
                     if status != 0 {
                         return status;
@@ -283,10 +586,7 @@ impl Sandbox {
             return Err(err);
         }
 
-        let slirp = match SlirpHelper::spawn(
-            pid,
-            Path::new(&format!("{}/dabba-slirp.sock", util::get_base_path())),
-        ) {
+        let slirp = match SlirpHelper::spawn(pid, &container.slirp_socket()) {
             Ok(slirp) => slirp,
             Err(err) => {
                 log::warn!("Failed to setup Slirp: {err}");
@@ -300,7 +600,7 @@ impl Sandbox {
 
         slirp.wait_until_ready().expect("failed to wait for slirp!");
 
-        for port in ports {
+        for port in &container.state.ports {
             if let Err(err) = slirp.expose_port(port) {
                 log::warn!("Failed to expose port: {err}");
                 ipc.send_from_parent(ParentEvent::SlirpFailure)
@@ -317,21 +617,185 @@ impl Sandbox {
         match ipc.recv_in_parent().expect("failed to recv in parent!") {
             ChildEvent::InitFailed => {
                 log::warn!("Child notified failure in parent");
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "child init failed",
-                ))
+                Err(std::io::Error::other("child init failed"))
+            }
+            ChildEvent::InitSuccess => {
+                let pty = if stdio.tty {
+                    match Pty::open(pid) {
+                        Ok(pty) => {
+                            ipc.send_pty_from_parent(pty.index())
+                                .expect("failed to send from parent!");
+                            Some(pty)
+                        }
+                        Err(err) => {
+                            log::warn!("Failed to allocate a pty: {err}");
+                            ipc.send_from_parent(ParentEvent::PtyFailure)
+                                .expect("failed to send from parent!");
+
+                            nix::sys::wait::waitpid(pid, None).expect("failed to wait!");
+                            return Err(err);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                Ok(Self {
+                    pid,
+                    _slirp: slirp,
+                    pty,
+                    stdio,
+                    exit_code: None,
+                    stop_signal,
+                    stop_timeout: Duration::from_secs(container.state.stop_timeout),
+                })
             }
-            ChildEvent::InitSuccess => Ok(Self { pid, _slirp: slirp }),
         }
     }
 
-    pub fn wait(&mut self) -> Result<(), std::io::Error> {
-        let status = nix::sys::wait::waitpid(self.pid, None)?;
-        log::info!("Sandbox exited with status {status:?}");
+    /// Wait for the sandbox to exit, relaying it's terminal meanwhile
+    /// Returns the exit code of the container's command
+    pub fn wait(&mut self) -> Result<i32, std::io::Error> {
+        if let Some(exit_code) = self.exit_code {
+            return Ok(exit_code);
+        }
+
+        let _forward = ForwardSignals::install(
+            self.pid,
+            self.stop_signal,
+            self.stop_timeout,
+            self.pty.is_some(),
+        )?;
+
+        if let Some(pty) = self.pty.take() {
+            pty.relay(self.stdio.interactive)?;
+        }
+
+        let (_, status) = Self::waitpid(Some(self.pid), 0)?.expect("unreachable, no WNOHANG");
+        let exit_code = Self::exit_code(status);
+        log::info!("Sandbox exited with {exit_code}");
+
+        self.exit_code = Some(exit_code);
+
+        Ok(exit_code)
+    }
+
+    /// Join the namespaces of a running container, in the order they
+    /// need to be entered. The user namespace grants us the capabilities
+    /// required for the rest
+    fn join_namespaces(pid: Pid) -> Result<(), std::io::Error> {
+        const NAMESPACES: [(&str, CloneFlags); 7] = [
+            ("user", CloneFlags::CLONE_NEWUSER),
+            ("mnt", CloneFlags::CLONE_NEWNS),
+            ("pid", CloneFlags::CLONE_NEWPID),
+            ("net", CloneFlags::CLONE_NEWNET),
+            ("ipc", CloneFlags::CLONE_NEWIPC),
+            ("uts", CloneFlags::CLONE_NEWUTS),
+            ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+        ];
+
+        // Open all of them upfront, /proc is the container's once we've
+        // joined it's mount namespace
+        let namespaces = NAMESPACES
+            .iter()
+            .map(|(name, flag)| {
+                std::fs::File::open(format!("/proc/{pid}/ns/{name}")).map(|file| (file, *flag))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (file, flag) in namespaces {
+            log::info!("Joining {flag:?}");
+            nix::sched::setns(file, flag)?;
+        }
 
         Ok(())
     }
+
+    /// Runs in the child forked by `exec`, only returns on failure
+    fn exec_child(
+        config: &ImageConfigRuntime,
+        env: &[String],
+        command: &[String],
+        working_dir: Option<&str>,
+        pty: Option<u32>,
+        stdio: StdioConfig,
+    ) -> Result<Infallible, std::io::Error> {
+        Self::die_with_parent()?;
+        Self::new_session()?;
+        Self::connect_stdio(pty, stdio)?;
+        Self::drop_privs()?;
+
+        Self::setup_env(config, env);
+        Self::enter_working_dir(working_dir.or(config.working_dir.as_deref()))?;
+
+        util::close_fds()?;
+
+        let (argv0, args) = command
+            .split_first()
+            .ok_or_else(|| std::io::Error::other("no command passed"))?;
+
+        log::info!("Executing program '{argv0}' with args: {args:?}");
+
+        Err(std::process::Command::new(argv0).args(args).exec())
+    }
+
+    /// Run `command` inside the running `container`, with the same
+    /// environment as it's own command plus `env`. Returns it's exit code
+    pub fn exec(
+        container: &Container,
+        command: &[String],
+        env: &[String],
+        working_dir: Option<&str>,
+        stdio: StdioConfig,
+    ) -> Result<i32, std::io::Error> {
+        let pid = match (container.is_running(), container.state.pid) {
+            (true, Some(pid)) => Pid::from_raw(pid),
+            _ => {
+                return Err(std::io::Error::other(format!(
+                    "Container {} is not running",
+                    container.short_id()
+                )))
+            }
+        };
+
+        // Allocated through our /proc, before joining the mount namespace
+        let pty = stdio.tty.then(|| Pty::open(pid)).transpose()?;
+
+        Self::join_namespaces(pid)?;
+
+        let env = [container.state.env.as_slice(), env].concat();
+
+        // The PID namespace only applies to our children
+        let child = match unsafe { nix::unistd::fork()? } {
+            ForkResult::Parent { child } => child,
+            ForkResult::Child => {
+                let err = Self::exec_child(
+                    &container.state.config,
+                    &env,
+                    command,
+                    working_dir,
+                    pty.as_ref().map(Pty::index),
+                    stdio,
+                )
+                .unwrap_err();
+
+                log::error!("Failed to exec: {err}");
+                std::process::exit(127);
+            }
+        };
+
+        log::info!("Executed command with pid {child}");
+
+        if let Some(pty) = pty {
+            pty.relay(stdio.interactive)?;
+        }
+
+        let (_, status) = Self::waitpid(Some(child), 0)?.expect("unreachable, no WNOHANG");
+        let exit_code = Self::exit_code(status);
+        log::info!("Command exited with {exit_code}");
+
+        Ok(exit_code)
+    }
 }
 
 impl Drop for Sandbox {
@@ -2,12 +2,13 @@ use crate::util;
 use nix::poll::{PollFd, PollFlags};
 use nix::sys::signal::Signal;
 use nix::unistd::Pid;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::io::{Read, Write};
 use std::os::fd::{AsRawFd, OwnedFd};
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Output, Stdio};
+use std::str::FromStr;
 
 /// Helper for spawning slirp4netns and performing IPC with pipes
 pub struct SlirpHelper {
@@ -18,7 +19,7 @@ pub struct SlirpHelper {
     socket_path: PathBuf,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PortMapping {
     proto: String,
     host_port: u16,
@@ -31,31 +32,47 @@ struct HostFwdRequest {
     arguments: PortMapping,
 }
 
-/// XXX this should implement the FromStr trait instead
-impl PortMapping {
+/// Same format as parsed
+impl std::fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}/{}", self.host_port, self.guest_port, self.proto)
+    }
+}
+
+impl FromStr for PortMapping {
+    type Err = std::io::Error;
+
     // HOST:GUEST/PROTO
     // 8080:80/tcp
-    pub fn from_str(mapping: &str) -> Self {
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
         log::info!("Parsing mapping: {mapping}");
 
-        let mut port_proto = mapping.split('/');
-
-        let ports = port_proto.next().expect("no ports passed");
-        let proto = port_proto.next().expect("no protocol passed");
-
-        let mut host_guest = ports.split(':');
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid port mapping '{mapping}': {reason}"),
+            )
+        };
 
-        let host = host_guest.next().expect("no host port");
-        let guest = host_guest.next().expect("no guest port");
+        let (ports, proto) = mapping
+            .split_once('/')
+            .ok_or_else(|| invalid("no protocol passed"))?;
+        let (host, guest) = ports
+            .split_once(':')
+            .ok_or_else(|| invalid("no guest port"))?;
 
-        Self {
+        Ok(Self {
             proto: match proto {
                 "tcp" | "udp" => proto.to_string(),
-                _ => panic!("Invalid protocol: {proto}"),
+                _ => return Err(invalid("protocol must be tcp or udp")),
             },
-            host_port: host.parse::<u16>().expect("host port overflows!"),
-            guest_port: guest.parse::<u16>().expect("guest port overflows!"),
-        }
+            host_port: host
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid host port"))?,
+            guest_port: guest
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid guest port"))?,
+        })
     }
 }
 
@@ -114,10 +131,9 @@ impl SlirpHelper {
         if (pollfd[0].revents().expect("failed to get revents!") & PollFlags::POLLIN)
             != PollFlags::POLLIN
         {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Did not receive POLLIN event after {TIMEOUT}ms"),
-            ));
+            return Err(std::io::Error::other(format!(
+                "Did not receive POLLIN event after {TIMEOUT}ms"
+            )));
         }
 
         let mut notification_buf = [0];
@@ -157,10 +173,7 @@ impl SlirpHelper {
         }
 
         if let Some(error) = parsed.get("error") {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Slirp Error: {error}"),
-            ));
+            return Err(std::io::Error::other(format!("Slirp Error: {error}")));
         }
 
         panic!("Got invalid JSON! {parsed}");
@@ -1,62 +1,1212 @@
-use crate::registry::{Manifest, RegistryClient};
-use flate2::read::GzDecoder;
+use crate::digest::{self, Digest, VerifyingReader};
+use crate::layer;
+use crate::progress::{Progress, ProgressReader, Status};
+use crate::reference::Reference;
+use crate::registry::{BlobPush, ImageConfig, Manifest, ManifestConfig, RegistryClient};
+use crate::util;
+use nix::fcntl::FlockArg;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
-use tar::Archive;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+mod archive;
+
+/// What a reference resolved to when it was last pulled
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageRecord {
+    /// Digest of the manifest for our platform, not of the index
+    pub manifest: String,
+    pub config: String,
+    /// Seconds since the epoch
+    pub pulled: u64,
+}
+
+/// Maps `registry/repository:tag` to the image it points to
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    images: BTreeMap<String, ImageRecord>,
+}
+
+/// A locally available image, with all of it's layers extracted
+#[derive(Debug)]
+pub struct Image {
+    /// Digest of the manifest, identifies the image
+    pub digest: String,
+    pub manifest: Manifest,
+    pub config: ImageConfig,
+    /// In the same order as the manifest's layers
+    pub layers: Vec<PathBuf>,
+}
+
+/// Held for as long as a container uses an image, so that the image can't
+/// be removed from under it
+pub struct ImageLease {
+    _file: File,
+}
+
+/// What `Storage::prune` removes on top of unreferenced content
+#[derive(Clone, Debug, Default)]
+pub struct PruneOptions {
+    /// Remove every image that isn't used by a container
+    pub all: bool,
+    /// Remove images pulled longer ago than this
+    pub max_age: Option<Duration>,
+    /// Remove the least recently pulled images until the store fits
+    pub max_size: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    /// References that were removed
+    pub images: Vec<String>,
+    /// Blobs, layers and downloads that were deleted
+    pub deleted: usize,
+    /// Bytes freed
+    pub reclaimed: u64,
+}
+
+/// Space used by the store, in bytes
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub images: usize,
+    /// Images used by running containers
+    pub active: usize,
+    pub images_size: u64,
+    /// Freed by removing all unused images
+    pub images_reclaimable: u64,
+    /// Content not reachable from any image, freed by a prune
+    pub unreferenced: usize,
+    pub unreferenced_size: u64,
+    pub stale_downloads: usize,
+    pub stale_downloads_size: u64,
+}
+
+/// When to contact the registry for an image, `--pull=<policy>`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PullPolicy {
+    /// Always fetch the manifest, only downloading layers that changed
+    Always,
+    /// Pull only if the image isn't available locally
+    #[default]
+    Missing,
+    /// Never touch the network
+    Never,
+}
+
+impl FromStr for PullPolicy {
+    type Err = std::io::Error;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "always" => Ok(Self::Always),
+            "missing" => Ok(Self::Missing),
+            "never" => Ok(Self::Never),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid pull policy '{policy}', expected always, missing or never"),
+            )),
+        }
+    }
+}
+
+/// Local image store
+/// blobs/sha256/<hex>   Manifests, configs and compressed layers, as pulled
+/// layers/sha256/<hex>  Extracted layers, named by the compressed digest
+/// downloads/           Partial downloads, moved to blobs/ once verified
+/// locks/sha256/<hex>   Held exclusively while a layer is downloaded
+/// leases/sha256/<hex>  Held shared by containers using an image
+/// index.json           References and the manifest and config they map to
+/// gc.lock              Held shared by pulls, exclusively by removals
+/// index.lock           Serializes updates to the index
+/// import.tmp-<pid>/    Archives being imported, see `archive`
 pub struct Storage {
     cache_dir: PathBuf,
 }
 
 impl Storage {
+    /// Attempts at downloading a blob before giving up, each attempt resumes
+    /// from where the previous one stopped
+    const MAX_ATTEMPTS: usize = 3;
+    /// Layers downloaded at the same time, matches docker's default
+    const MAX_CONCURRENT_DOWNLOADS: usize = 3;
+    /// Partial downloads untouched for this long are deleted by a prune
+    const STALE_DOWNLOAD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+    /// Layers are extracted to `<hex>.extracting` and renamed once complete
+    const EXTRACTING_SUFFIX: &'static str = ".extracting";
+    /// Small files are written to `<name>.tmp-<pid>` and renamed
+    const TMP_SUFFIX: &'static str = ".tmp-";
+
     pub fn new(cache_dir: &Path) -> Result<Self, std::io::Error> {
         std::fs::create_dir_all(cache_dir)?;
 
-        Ok(Self {
+        let storage = Self {
             cache_dir: cache_dir.to_path_buf(),
+        };
+        storage.scrub()?;
+
+        Ok(storage)
+    }
+
+    /// Take an advisory lock on `path`, creating it if needed. `None` if the
+    /// lock is non-blocking and held elsewhere
+    /// The lock is held until the returned file is dropped, or until we die
+    fn flock(path: &Path, arg: FlockArg) -> Result<Option<File>, std::io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        match nix::fcntl::flock(file.as_raw_fd(), arg) {
+            Ok(()) => Ok(Some(file)),
+            Err(nix::errno::Errno::EWOULDBLOCK) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Try a non-blocking lock first so that we can tell the user what
+    /// we're waiting on
+    fn flock_wait(path: &Path, exclusive: bool, what: &str) -> Result<File, std::io::Error> {
+        let (nonblock, block) = if exclusive {
+            (FlockArg::LockExclusiveNonblock, FlockArg::LockExclusive)
+        } else {
+            (FlockArg::LockSharedNonblock, FlockArg::LockShared)
+        };
+
+        if let Some(file) = Self::flock(path, nonblock)? {
+            return Ok(file);
+        }
+
+        log::info!("Waiting for {what}");
+
+        Self::flock(path, block)?.ok_or_else(|| {
+            std::io::Error::other(format!("Blocking lock on {path:?} returned early"))
         })
     }
 
-    /// Download all the uncached layers for the given image
-    /// Issues: Not very rpbust, downloads the whole layer in memory, etc.
+    /// Pulls hold this shared so that removals, which take it exclusively,
+    /// never delete content that a pull has written but not yet indexed
+    fn lock_gc(&self, exclusive: bool) -> Result<File, std::io::Error> {
+        Self::flock_wait(
+            &self.cache_dir.join("gc.lock"),
+            exclusive,
+            if exclusive {
+                "running pulls to finish"
+            } else {
+                "a running removal to finish"
+            },
+        )
+    }
+
+    /// Per-digest lock, so that concurrent pulls of the same layer wait for
+    /// each other rather than writing to the same files
+    fn lock_digest(&self, digest: &str) -> Result<File, std::io::Error> {
+        Self::flock_wait(
+            &self.lock_path(digest)?,
+            true,
+            &format!("another pull of {digest}"),
+        )
+    }
+
+    /// Whether anyone holds the per-digest lock
+    fn is_locked(&self, digest: &str) -> Result<bool, std::io::Error> {
+        Ok(Self::flock(&self.lock_path(digest)?, FlockArg::LockExclusiveNonblock)?.is_none())
+    }
+
+    /// Remove what interrupted pulls left behind, extractions and temporary
+    /// files that no live process is working on
+    fn scrub(&self) -> Result<(), std::io::Error> {
+        // Imports are staged at the top level
+        for entry in std::fs::read_dir(&self.cache_dir)? {
+            let path = entry?.path();
+
+            if Self::is_stale_tmp(&path) {
+                log::info!("Removing leftover {path:?}");
+                util::remove_tree(&path)?;
+            }
+        }
+
+        for dir in ["blobs", "layers"] {
+            let algorithms = match std::fs::read_dir(self.cache_dir.join(dir)) {
+                Ok(algorithms) => algorithms,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            for algorithm in algorithms {
+                let algorithm = algorithm?;
+
+                for entry in std::fs::read_dir(algorithm.path())? {
+                    let path = entry?.path();
+                    let name = path
+                        .file_name()
+                        .expect("directory entry has a name")
+                        .to_string_lossy()
+                        .into_owned();
+
+                    let stale = if let Some(hex) = name.strip_suffix(Self::EXTRACTING_SUFFIX) {
+                        let digest = format!("{}:{hex}", algorithm.file_name().to_string_lossy());
+                        !self.is_locked(&digest)?
+                    } else {
+                        Self::is_stale_tmp(&path)
+                    };
+
+                    if stale {
+                        log::info!("Removing leftover {path:?}");
+                        util::remove_tree(&path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Temporary files are suffixed with the pid of the process writing them
+    fn is_stale_tmp(path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .expect("directory entry has a name")
+            .to_string_lossy();
+
+        name.rsplit_once(Self::TMP_SUFFIX).is_some_and(|(_, pid)| {
+            !pid.parse::<u32>()
+                .is_ok_and(|pid| Path::new(&format!("/proc/{pid}")).exists())
+        })
+    }
+
+    /// Compressed blobs are downloaded here before being extracted
+    fn downloads_dir(&self) -> PathBuf {
+        self.cache_dir.join("downloads")
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.cache_dir.join("index.json")
+    }
+
+    /// `<dir>/<algorithm>/<hex>`, the digest is validated first so that it
+    /// can't be used to escape the store
+    fn content_path(&self, dir: &str, digest: &str) -> Result<PathBuf, std::io::Error> {
+        let digest: Digest = digest.parse()?;
+
+        Ok(self
+            .cache_dir
+            .join(dir)
+            .join(digest.algorithm.to_string())
+            .join(digest.hex))
+    }
+
+    fn blob_path(&self, digest: &str) -> Result<PathBuf, std::io::Error> {
+        self.content_path("blobs", digest)
+    }
+
+    fn layer_path(&self, digest: &str) -> Result<PathBuf, std::io::Error> {
+        self.content_path("layers", digest)
+    }
+
+    /// Write to a temporary file first so that readers never observe a
+    /// partially written file
+    fn write_atomic(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Unique per process so that concurrent writers don't clobber each
+        // other's temporary files
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!("{}{}", Self::TMP_SUFFIX, std::process::id()));
+
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Store a small blob such as a manifest or config after verifying it
+    pub fn write_blob(&self, digest: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        digest::verify_bytes(data, digest)?;
+        Self::write_atomic(&self.blob_path(digest)?, data)
+    }
+
+    /// Read back a small blob, checking that it hasn't been tampered with
+    pub fn read_blob(&self, digest: &str) -> Result<Vec<u8>, std::io::Error> {
+        let data = std::fs::read(self.blob_path(digest)?)?;
+        digest::verify_bytes(&data, digest)?;
+
+        Ok(data)
+    }
+
+    fn read_index(&self) -> Result<Index, std::io::Error> {
+        match std::fs::read_to_string(self.index_path()) {
+            Ok(index) => util::serde_deserialize_or_err(&index),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_index(&self, index: &Index) -> Result<(), std::io::Error> {
+        Self::write_atomic(
+            &self.index_path(),
+            &util::serde_result_to_ureq(serde_json::to_vec_pretty(index))?,
+        )
+    }
+
+    /// Point the reference at an image whose content is all stored
+    fn record(
+        &self,
+        reference: &Reference,
+        manifest: &str,
+        config: &str,
+    ) -> Result<(), std::io::Error> {
+        let _index_lock = Self::flock_wait(
+            &self.cache_dir.join("index.lock"),
+            true,
+            "another update to the index",
+        )?;
+
+        let mut index = self.read_index()?;
+        index.images.insert(
+            reference.to_string(),
+            ImageRecord {
+                manifest: manifest.to_string(),
+                config: config.to_string(),
+                pulled: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs(),
+            },
+        );
+
+        self.write_index(&index)
+    }
+
+    /// Look up what a reference pointed to when it was last pulled
+    pub fn lookup(&self, reference: &Reference) -> Result<Option<ImageRecord>, std::io::Error> {
+        Ok(self.read_index()?.images.remove(&reference.to_string()))
+    }
+
+    /// Layers that we're expected to download, non-distributable layers
+    /// must be provided out of band
+    fn distributable_layers(manifest: &Manifest) -> Vec<&ManifestConfig> {
+        manifest
+            .layers
+            .iter()
+            .filter(|layer| !layer::is_foreign(&layer.media_type))
+            .collect()
+    }
+
+    /// Download the compressed blob to a partial file, resuming from the
+    /// partial file left behind by a previous attempt if there is one
+    fn fetch_blob(
+        &self,
+        registry: &RegistryClient,
+        descriptor: &ManifestConfig,
+        progress: &Progress,
+        idx: usize,
+    ) -> Result<PathBuf, std::io::Error> {
+        let downloads_dir = self.downloads_dir();
+        std::fs::create_dir_all(&downloads_dir)?;
+
+        let path = downloads_dir.join(format!("{}.partial", descriptor.digest));
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let mut last_err = None;
+
+        for attempt in 1..=Self::MAX_ATTEMPTS {
+            let mut offset = file.metadata()?.len();
+
+            // Can't be resumed, something else must've written to the file
+            if offset > descriptor.size {
+                log::warn!("Partial download {path:?} is larger than the blob, restarting");
+
+                file.set_len(0)?;
+                offset = 0;
+            }
+
+            if offset == descriptor.size {
+                return Ok(path);
+            }
+
+            if offset > 0 {
+                log::info!(
+                    "Resuming download of {} from byte {offset}/{}",
+                    descriptor.digest,
+                    descriptor.size
+                );
+            }
+
+            let result = registry
+                .get_blob_range(descriptor, offset)
+                .and_then(|(body, start)| {
+                    if start != offset {
+                        log::info!("Registry ignored the range request, restarting download");
+                        file.set_len(0)?;
+                    }
+
+                    progress.start_download(idx, start);
+
+                    // Never write past the size in the descriptor
+                    let mut body =
+                        ProgressReader::new(body.take(descriptor.size - start), progress, idx);
+                    std::io::copy(&mut body, &mut file)
+                });
+
+            if let Err(err) = result {
+                log::warn!(
+                    "Attempt {attempt}/{} at downloading {} failed: {err}",
+                    Self::MAX_ATTEMPTS,
+                    descriptor.digest
+                );
+
+                last_err = Some(err);
+            }
+        }
+
+        if file.metadata()?.len() == descriptor.size {
+            return Ok(path);
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Download of {} ended early", descriptor.digest),
+            )
+        }))
+    }
+
+    /// Decompress and unpack the layer while hashing it, in a single pass
+    /// The digest can only be checked once the whole blob has been read, so
+    /// the caller must throw away the extracted contents on failure
+    fn extract_layer(
+        mut blob: VerifyingReader<impl Read>,
+        media_type: &str,
+        path: &Path,
+    ) -> Result<(), std::io::Error> {
+        layer::unpack_blob(&mut blob, media_type, path)?;
+
+        // Anything after the end of the compressed stream is still part of
+        // the digest
+        std::io::copy(&mut blob, &mut std::io::sink())?;
+
+        blob.verify()
+    }
+
+    /// Download and extract a single layer, unless it's already cached
+    fn fetch_layer(
+        &self,
+        registry: &RegistryClient,
+        descriptor: &ManifestConfig,
+        progress: &Progress,
+        idx: usize,
+    ) -> Result<PathBuf, std::io::Error> {
+        let layer = &descriptor.digest;
+
+        let path = self.layer_path(layer)?;
+
+        // Held until we return, anyone else pulling this layer finds it
+        // extracted once they get the lock
+        let _lock = self.lock_digest(layer)?;
+
+        if path.try_exists()? {
+            log::debug!("Found layer {path:?}, skipping download");
+            progress.set_status(idx, Status::Cached);
+
+            return Ok(path);
+        }
+
+        let stored_path = self.blob_path(layer)?;
+
+        let blob_path = if stored_path.try_exists()? {
+            log::debug!("Found blob {stored_path:?}, skipping download");
+            stored_path
+        } else {
+            self.fetch_blob(registry, descriptor, progress, idx)?
+        };
+
+        progress.set_status(idx, Status::Extracting);
+        self.install_layer(&blob_path, descriptor)?;
+        progress.set_status(idx, Status::Done);
+
+        Ok(path)
+    }
+
+    /// Extract a complete blob and move it into the store along with the
+    /// extracted layer, the caller must hold the digest's lock
+    /// The blob is deleted if it's corrupt
+    fn install_layer(
+        &self,
+        blob_path: &Path,
+        descriptor: &ManifestConfig,
+    ) -> Result<PathBuf, std::io::Error> {
+        let layer = &descriptor.digest;
+
+        let path = self.layer_path(layer)?;
+        let stored_path = self.blob_path(layer)?;
+
+        let blob = VerifyingReader::new(File::open(blob_path)?, layer, descriptor.size)?;
+
+        // Extracted next to the final path and renamed once complete, so
+        // that a crash never leaves a half-populated layer behind
+        let mut extract_path = path.as_os_str().to_owned();
+        extract_path.push(Self::EXTRACTING_SUFFIX);
+        let extract_path = PathBuf::from(extract_path);
+
+        util::remove_tree(&extract_path)?;
+
+        if let Err(err) = Self::extract_layer(blob, &descriptor.media_type, &extract_path) {
+            log::warn!("Failed to extract layer {layer}: {err}");
+
+            if let Err(_remove_err) = util::remove_tree(&extract_path) {
+                log::warn!("Failed to cleanup directory: {extract_path:?}");
+            }
+
+            // A complete download that fails to verify or extract is
+            // corrupt, resuming it would never succeed
+            if let Err(_remove_err) = std::fs::remove_file(blob_path) {
+                log::warn!("Failed to cleanup download: {blob_path:?}");
+            }
+
+            return Err(err);
+        }
+
+        // Keep the compressed blob around, it's needed to push or save the
+        // image without recompressing it
+        if blob_path != stored_path {
+            std::fs::create_dir_all(stored_path.parent().expect("blob path has a parent"))?;
+            std::fs::rename(blob_path, &stored_path)?;
+        }
+
+        std::fs::rename(&extract_path, &path)?;
+
+        Ok(path)
+    }
+
+    /// Download all the uncached layers for the given image, a bounded
+    /// number of layers are downloaded and extracted concurrently as each
+    /// layer is extracted into it's own directory
+    /// The returned paths are in the same order as the manifest's layers
     pub fn download_layers(
         &self,
         registry: &RegistryClient,
         manifest: &Manifest,
-    ) -> Result<Vec<PathBuf>, ureq::Error> {
+    ) -> Result<Vec<PathBuf>, std::io::Error> {
+        for layer in manifest
+            .layers
+            .iter()
+            .filter(|layer| layer::is_foreign(&layer.media_type))
+        {
+            log::warn!(
+                "Skipping non-distributable layer {} ({}), it must be provided out of band",
+                layer.digest,
+                layer.media_type
+            );
+        }
+
+        let layers = Self::distributable_layers(manifest);
+        let progress = Progress::new(
+            &layers
+                .iter()
+                .map(|layer| (layer.digest.as_str(), layer.size))
+                .collect::<Vec<_>>(),
+        );
+
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+
+        let mut results: Vec<Option<Result<PathBuf, std::io::Error>>> =
+            layers.iter().map(|_| None).collect();
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..Self::MAX_CONCURRENT_DOWNLOADS.min(layers.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::SeqCst);
+
+                            // Don't start any new work once a layer fails
+                            if idx >= layers.len() || failed.load(Ordering::SeqCst) {
+                                break;
+                            }
+
+                            let result = self.fetch_layer(registry, layers[idx], &progress, idx);
+
+                            if result.is_err() {
+                                progress.set_status(idx, Status::Failed);
+                                failed.store(true, Ordering::SeqCst);
+                            }
+
+                            done.push((idx, result));
+                        }
+
+                        done
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                for (idx, result) in worker.join().expect("download worker panicked") {
+                    results[idx] = Some(result);
+                }
+            }
+        });
+
+        progress.finish();
+
         let mut out = Vec::new();
 
-        for layer in &manifest.layers {
-            let layer = &layer.digest;
+        for result in results {
+            match result {
+                Some(Ok(path)) => out.push(path),
+                Some(Err(err)) => return Err(err),
+                // Skipped due to another layer failing, the error is
+                // returned when we reach it
+                None => {}
+            }
+        }
 
-            let path = self.cache_dir.join(layer);
+        Ok(out)
+    }
 
-            if path.try_exists().expect("can't check path existence") {
-                log::info!("Found layer {path:?}, skipping download");
-            } else {
-                log::info!("Downloading layer {layer}");
+    /// Pull the image for the registry's reference, storing the manifest and
+    /// config alongside the layers and recording the reference in the index
+    pub fn pull(&self, registry: &RegistryClient) -> Result<Image, std::io::Error> {
+        let _gc = self.lock_gc(false)?;
 
-                // TODO Verify checksums
-                let layer_bytes = registry.get_blob(layer)?;
+        let (manifest, raw_manifest) = registry.get_manifest()?;
+        log::info!("Image Manifest: {manifest:#?}");
 
-                let mut gzip_reader = GzDecoder::new(&layer_bytes[..]);
-                let mut archive = Archive::new(&mut gzip_reader);
+        let manifest_digest = digest::sha256(raw_manifest.as_bytes());
+        self.write_blob(&manifest_digest, raw_manifest.as_bytes())?;
 
-                if let Err(err) = archive.unpack(&path) {
-                    log::warn!("Failed to extract layer {layer}: {err}");
+        let (config, raw_config) = registry.get_image_config(&manifest)?;
+        log::info!("Image Config: {config:#?}");
 
-                    if let Err(_remove_err) = std::fs::remove_dir_all(&path) {
-                        log::warn!("Failed to cleanup directory: {path:?}");
-                    }
+        self.write_blob(&manifest.config.digest, &raw_config)?;
+
+        let layers = self.download_layers(registry, &manifest)?;
+
+        self.record(
+            registry.reference(),
+            &manifest_digest,
+            &manifest.config.digest,
+        )?;
+
+        Ok(Image {
+            digest: manifest_digest,
+            manifest,
+            config,
+            layers,
+        })
+    }
+
+    /// Upload a stored image to the registry's repository, under the
+    /// registry's reference. Returns the manifest's digest
+    pub fn push(
+        &self,
+        reference: &Reference,
+        registry: &RegistryClient,
+    ) -> Result<String, std::io::Error> {
+        let _gc = self.lock_gc(false)?;
+
+        let record = self.lookup(reference)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No such image: '{reference}'"),
+            )
+        })?;
+
+        let raw_manifest = String::from_utf8(self.read_blob(&record.manifest)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let manifest: Manifest = util::serde_deserialize_or_err(&raw_manifest)?;
+
+        let layers = Self::distributable_layers(&manifest);
+
+        if layers.len() != manifest.layers.len() {
+            log::warn!("Not pushing non-distributable layers, they're fetched from elsewhere");
+        }
+
+        // Registries refuse manifests that refer to blobs they don't have
+        for descriptor in layers.into_iter().chain([&manifest.config]) {
+            let blob = File::open(self.blob_path(&descriptor.digest)?)?;
+
+            log::info!(
+                "Pushing {} ({})",
+                descriptor.digest,
+                util::human_size(descriptor.size)
+            );
+
+            match registry.push_blob(descriptor, blob)? {
+                BlobPush::Exists => log::info!("{} already exists", descriptor.digest),
+                BlobPush::Mounted => log::info!("Mounted {}", descriptor.digest),
+                BlobPush::Uploaded => log::info!("Uploaded {}", descriptor.digest),
+            }
+        }
+
+        let media_type = if manifest.media_type.is_empty() {
+            RegistryClient::MANIFEST_V1
+        } else {
+            &manifest.media_type
+        };
+
+        registry.put_manifest(&raw_manifest, media_type)
+    }
+
+    /// Load a previously pulled image without touching the network, `None`
+    /// if the reference was never pulled or some of it's content is missing
+    pub fn resolve(&self, reference: &Reference) -> Result<Option<Image>, std::io::Error> {
+        let Some(record) = self.lookup(reference)? else {
+            return Ok(None);
+        };
+
+        let (manifest, config) = match self.load(&record) {
+            Ok(loaded) => loaded,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut layers = Vec::new();
+
+        for layer in Self::distributable_layers(&manifest) {
+            let path = self.layer_path(&layer.digest)?;
+
+            if !path.try_exists()? {
+                log::info!("Layer {} of {reference} is missing", layer.digest);
+                return Ok(None);
+            }
+
+            layers.push(path);
+        }
+
+        Ok(Some(Image {
+            digest: record.manifest,
+            manifest,
+            config,
+            layers,
+        }))
+    }
+
+    /// Parse the stored manifest and config of an image
+    pub fn load(&self, record: &ImageRecord) -> Result<(Manifest, ImageConfig), std::io::Error> {
+        let manifest = self.read_blob(&record.manifest)?;
+        let config = self.read_blob(&record.config)?;
 
-                    return Err(err.into());
+        Ok((
+            util::serde_result_to_ureq(serde_json::from_slice(&manifest))?,
+            util::serde_result_to_ureq(serde_json::from_slice(&config))?,
+        ))
+    }
+
+    /// All the references in the index, sorted
+    pub fn images(&self) -> Result<Vec<(String, ImageRecord)>, std::io::Error> {
+        Ok(self.read_index()?.images.into_iter().collect())
+    }
+
+    fn lock_path(&self, digest: &str) -> Result<PathBuf, std::io::Error> {
+        self.content_path("locks", digest)
+    }
+
+    fn lease_path(&self, digest: &str) -> Result<PathBuf, std::io::Error> {
+        self.content_path("leases", digest)
+    }
+
+    /// Mark the image as in use until the lease is dropped, the lock is
+    /// released by the kernel even if we're killed
+    pub fn lease(&self, image: &Image) -> Result<ImageLease, std::io::Error> {
+        let file = Self::flock(&self.lease_path(&image.digest)?, FlockArg::LockShared)?
+            .expect("blocking lock returned early");
+
+        Ok(ImageLease { _file: file })
+    }
+
+    /// Whether any container holds a lease on the image
+    pub fn is_in_use(&self, digest: &str) -> Result<bool, std::io::Error> {
+        let file = match File::open(self.lease_path(digest)?) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        match nix::fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(false),
+            Err(nix::errno::Errno::EWOULDBLOCK) => Ok(true),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Digests of the manifest, config and layers making up an image
+    fn content_of(&self, manifest_digest: &str) -> Result<Vec<String>, std::io::Error> {
+        let manifest: Manifest =
+            util::serde_result_to_ureq(serde_json::from_slice(&self.read_blob(manifest_digest)?))?;
+
+        let mut digests = vec![manifest_digest.to_string(), manifest.config.digest];
+        digests.extend(manifest.layers.into_iter().map(|layer| layer.digest));
+
+        Ok(digests)
+    }
+
+    /// Digests of all the content reachable from the index and from the
+    /// images leased by containers, which may no longer be tagged
+    fn reachable(&self, index: &Index, leased: &HashSet<String>) -> HashSet<String> {
+        let mut reachable = HashSet::new();
+
+        let manifests = index
+            .images
+            .values()
+            .map(|record| &record.manifest)
+            .chain(leased);
+
+        for manifest in manifests {
+            match self.content_of(manifest) {
+                Ok(content) => reachable.extend(content),
+                Err(err) => {
+                    log::warn!("Can't read manifest {manifest}: {err}");
+                    reachable.insert(manifest.clone());
                 }
+            }
+        }
+
+        reachable
+    }
+
+    /// Manifests of the images currently leased by containers
+    fn leased(&self) -> Result<HashSet<String>, std::io::Error> {
+        let mut leased = HashSet::new();
 
-                log::info!("Successfully extracted layer {layer}");
+        for digest in self.stored_in("leases")?.into_keys() {
+            if self.is_in_use(&digest)? {
+                leased.insert(digest);
             }
+        }
+
+        Ok(leased)
+    }
+
+    /// Digests stored under `dir`, along with the space each one takes up
+    fn stored_in(&self, dir: &str) -> Result<BTreeMap<String, u64>, std::io::Error> {
+        let mut stored = BTreeMap::new();
+
+        let algorithms = match std::fs::read_dir(self.cache_dir.join(dir)) {
+            Ok(algorithms) => algorithms,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(stored),
+            Err(err) => return Err(err),
+        };
+
+        for algorithm in algorithms {
+            let algorithm = algorithm?;
 
-            out.push(path);
+            for entry in std::fs::read_dir(algorithm.path())? {
+                let entry = entry?;
+                let digest = format!(
+                    "{}:{}",
+                    algorithm.file_name().to_string_lossy(),
+                    entry.file_name().to_string_lossy()
+                );
+
+                // Leftover temporary files and such
+                if digest.parse::<Digest>().is_err() {
+                    continue;
+                }
+
+                stored.insert(digest, Self::disk_usage(&entry.path()));
+            }
         }
 
-        Ok(out)
+        Ok(stored)
+    }
+
+    /// All stored blobs and extracted layers, with their combined size
+    fn stored_content(&self) -> Result<BTreeMap<String, u64>, std::io::Error> {
+        let mut stored = self.stored_in("blobs")?;
+
+        for (digest, size) in self.stored_in("layers")? {
+            *stored.entry(digest).or_default() += size;
+        }
+
+        Ok(stored)
+    }
+
+    fn disk_usage(path: &Path) -> u64 {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return 0;
+        };
+
+        if !metadata.is_dir() {
+            return metadata.len();
+        }
+
+        std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| Self::disk_usage(&entry.path()))
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Partial downloads that haven't been touched in a while, left behind
+    /// by interrupted pulls
+    fn stale_downloads(&self) -> Result<Vec<(PathBuf, u64)>, std::io::Error> {
+        let mut stale = Vec::new();
+
+        let entries = match std::fs::read_dir(self.downloads_dir()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(stale),
+            Err(err) => return Err(err),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            let age = metadata.modified()?.elapsed().unwrap_or(Duration::ZERO);
+
+            // <digest>.partial
+            let downloading = match entry.file_name().to_string_lossy().strip_suffix(".partial") {
+                Some(digest) if digest.parse::<Digest>().is_ok() => self.is_locked(digest)?,
+                _ => false,
+            };
+
+            if age > Self::STALE_DOWNLOAD_AGE && !downloading {
+                stale.push((entry.path(), metadata.len()));
+            }
+        }
+
+        Ok(stale)
+    }
+
+    /// Remove all the stored content for a digest, the caller must hold the
+    /// gc lock exclusively so that nobody is waiting on the digest's lock
+    fn remove_content(&self, digest: &str) -> Result<(), std::io::Error> {
+        log::info!("Deleting {digest}");
+
+        util::remove_tree(&self.layer_path(digest)?)?;
+        util::remove_tree(&self.blob_path(digest)?)?;
+        util::remove_tree(&self.lease_path(digest)?)?;
+        util::remove_tree(&self.lock_path(digest)?)
+    }
+
+    /// Remove a reference from the index, along with the image's content
+    /// unless it's still used by another reference
+    /// Refuses to remove an image used by a container unless `force` is set
+    pub fn remove(&self, reference: &Reference, force: bool) -> Result<(), std::io::Error> {
+        let _gc = self.lock_gc(true)?;
+        let mut index = self.read_index()?;
+
+        let Some(record) = index.images.remove(&reference.to_string()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No such image: '{reference}'"),
+            ));
+        };
+
+        // Other references keep the content alive, only untag it
+        if self
+            .reachable(&index, &HashSet::new())
+            .contains(&record.manifest)
+        {
+            return self.write_index(&index);
+        }
+
+        if !force && self.is_in_use(&record.manifest)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                format!("Image '{reference}' is in use by a container, use -f to force"),
+            ));
+        }
+
+        // Content used by containers is kept even when forced, it's removed
+        // by a later prune once they exit
+        let reachable = self.reachable(&index, &self.leased()?);
+
+        let digests = self
+            .content_of(&record.manifest)
+            .unwrap_or_else(|_| vec![record.manifest.clone(), record.config.clone()]);
+
+        // Remove from the index first, so that a failure midway leaves
+        // unreferenced content behind rather than a broken image
+        self.write_index(&index)?;
+
+        for digest in digests {
+            if !reachable.contains(&digest) {
+                self.remove_content(&digest)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove images according to `options`, and then everything that isn't
+    /// reachable from the remaining images or from running containers
+    pub fn prune(&self, options: &PruneOptions) -> Result<PruneReport, std::io::Error> {
+        let _gc = self.lock_gc(true)?;
+        let mut index = self.read_index()?;
+        let leased = self.leased()?;
+        let stored = self.stored_content()?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_secs();
+
+        let mut report = PruneReport::default();
+
+        // Oldest first, so that the budget evicts the least recently pulled
+        let mut removable: Vec<(String, ImageRecord)> = index
+            .images
+            .iter()
+            .filter(|(_, record)| !leased.contains(&record.manifest))
+            .map(|(name, record)| (name.clone(), record.clone()))
+            .collect();
+        removable.sort_by_key(|(_, record)| record.pulled);
+
+        let mut removable = removable.into_iter().peekable();
+
+        while let Some((name, record)) = removable.peek() {
+            let expired = options
+                .max_age
+                .is_some_and(|max_age| now.saturating_sub(record.pulled) > max_age.as_secs());
+
+            if !options.all && !expired {
+                break;
+            }
+
+            index.images.remove(name);
+            report.images.push(name.clone());
+            removable.next();
+        }
+
+        if let Some(max_size) = options.max_size {
+            loop {
+                let reachable = self.reachable(&index, &leased);
+                let size: u64 = stored
+                    .iter()
+                    .filter(|(digest, _)| reachable.contains(*digest))
+                    .map(|(_, size)| size)
+                    .sum();
+
+                if size <= max_size {
+                    break;
+                }
+
+                let Some((name, _)) = removable.next() else {
+                    log::warn!(
+                        "Storage uses {}, but the remaining images are in use",
+                        util::human_size(size)
+                    );
+                    break;
+                };
+
+                index.images.remove(&name);
+                report.images.push(name);
+            }
+        }
+
+        // Same as `remove`, update the index before deleting anything
+        self.write_index(&index)?;
+
+        let reachable = self.reachable(&index, &leased);
+
+        for (digest, size) in stored {
+            if !reachable.contains(&digest) {
+                self.remove_content(&digest)?;
+
+                report.deleted += 1;
+                report.reclaimed += size;
+            }
+        }
+
+        for (path, size) in self.stale_downloads()? {
+            log::info!("Deleting stale download {path:?}");
+            std::fs::remove_file(&path)?;
+
+            report.deleted += 1;
+            report.reclaimed += size;
+        }
+
+        Ok(report)
+    }
+
+    /// Summarize the space used by images, unreferenced content and partial
+    /// downloads
+    pub fn usage(&self) -> Result<Usage, std::io::Error> {
+        let index = self.read_index()?;
+        let leased = self.leased()?;
+        let stored = self.stored_content()?;
+
+        let reachable = self.reachable(&index, &leased);
+        let in_use = self.reachable(&Index::default(), &leased);
+
+        let mut usage = Usage {
+            images: index.images.len(),
+            active: index
+                .images
+                .values()
+                .filter(|record| leased.contains(&record.manifest))
+                .count(),
+            ..Default::default()
+        };
+
+        for (digest, size) in stored {
+            if !reachable.contains(&digest) {
+                usage.unreferenced += 1;
+                usage.unreferenced_size += size;
+            } else {
+                usage.images_size += size;
+
+                if !in_use.contains(&digest) {
+                    usage.images_reclaimable += size;
+                }
+            }
+        }
+
+        for (_, size) in self.stale_downloads()? {
+            usage.stale_downloads += 1;
+            usage.stale_downloads_size += size;
+        }
+
+        Ok(usage)
+    }
+
+    /// Get an image according to the pull policy, only creating a registry
+    /// client if we actually need to talk to the registry
+    /// The image is leased before any removal gets a chance to run
+    pub fn get_image(
+        &self,
+        reference: &Reference,
+        policy: PullPolicy,
+    ) -> Result<(Image, ImageLease), std::io::Error> {
+        let _gc = self.lock_gc(false)?;
+
+        if policy != PullPolicy::Always {
+            if let Some(image) = self.resolve(reference)? {
+                log::info!("Found '{reference}' locally, not pulling");
+
+                let lease = self.lease(&image)?;
+                return Ok((image, lease));
+            }
+        }
+
+        if policy == PullPolicy::Never {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Image '{reference}' isn't available locally and the pull policy is never"),
+            ));
+        }
+
+        let image = self.pull(&RegistryClient::new(reference))?;
+        let lease = self.lease(&image)?;
+
+        Ok((image, lease))
     }
 }
@@ -0,0 +1,564 @@
+//! Moving images between machines without a registry, as `docker save`
+//! tarballs and OCI image layouts
+//! https://github.com/moby/moby/blob/master/image/spec/v1.2.md
+//! https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use super::Storage;
+use crate::digest::{self, Digest};
+use crate::layer::Compression;
+use crate::reference::Reference;
+use crate::registry::{ImageConfig, ImagePlatform, Manifest, ManifestConfig, RegistryClient};
+use crate::util;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+const OCI_LAYOUT: &str = "oci-layout";
+const OCI_INDEX: &str = "index.json";
+const DOCKER_MANIFEST: &str = "manifest.json";
+
+/// Either just the tag, or a full reference
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// Full reference, as written by containerd and docker
+const IMAGE_NAME_ANNOTATION: &str = "io.containerd.image.name";
+
+const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+
+/// oci-layout
+#[derive(Serialize, Deserialize)]
+struct OciLayout {
+    #[serde(rename = "imageLayoutVersion")]
+    image_layout_version: String,
+}
+
+/// index.json of an OCI layout, or a nested image index
+#[derive(Serialize, Deserialize)]
+struct LayoutIndex {
+    #[serde(rename = "schemaVersion")]
+    schema_version: i32,
+    #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    manifests: Vec<LayoutDescriptor>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutDescriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+    #[serde(default, skip_serializing)]
+    platform: Option<ImagePlatform>,
+}
+
+/// Entry of a docker archive's manifest.json
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    /// Paths relative to the root of the archive
+    config: String,
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, std::io::Error> {
+    util::serde_deserialize_or_err(&std::fs::read_to_string(path)?)
+}
+
+fn append_json(
+    builder: &mut tar::Builder<impl Write>,
+    name: &str,
+    value: &impl Serialize,
+) -> Result<(), std::io::Error> {
+    let data = util::serde_result_to_ureq(serde_json::to_vec(value))?;
+
+    let mut header = tar::Header::new_ustar();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    builder.append_data(&mut header, name, data.as_slice())
+}
+
+/// `blobs/<algorithm>/<hex>`, relative to the root of an OCI layout
+fn blob_name(digest: &str) -> Result<PathBuf, std::io::Error> {
+    let digest: Digest = digest.parse()?;
+
+    Ok(Path::new("blobs")
+        .join(digest.algorithm.to_string())
+        .join(digest.hex))
+}
+
+/// Paths in an archive must stay inside it, including the targets of
+/// symlinks, which `docker save` uses for layers shared between images
+/// Returns the resolved path, so that the file itself is moved or copied
+/// rather than the link
+fn archive_path(dir: &Path, path: impl AsRef<Path>) -> Result<PathBuf, std::io::Error> {
+    let relative = path.as_ref();
+    let outside = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Path {relative:?} points outside the archive"),
+        )
+    };
+
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside());
+    }
+
+    let root = dir.canonicalize()?;
+    let path = root.join(relative);
+
+    if std::fs::symlink_metadata(&path)?.is_symlink() {
+        log::debug!("Resolving symlink {relative:?}");
+    }
+
+    let resolved = path.canonicalize()?;
+
+    if !resolved.starts_with(&root) {
+        return Err(outside());
+    }
+
+    if !std::fs::metadata(&resolved)?.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Path {relative:?} is not a regular file"),
+        ));
+    }
+
+    Ok(resolved)
+}
+
+/// Name of an image in an OCI layout, `name` is used for images that don't
+/// have one, or only have a tag
+fn layout_name(
+    annotations: &BTreeMap<String, String>,
+    name: Option<&Reference>,
+) -> Result<Reference, std::io::Error> {
+    if let Some(full) = annotations.get(IMAGE_NAME_ANNOTATION) {
+        return full.parse();
+    }
+
+    match (annotations.get(REF_NAME_ANNOTATION), name) {
+        (Some(tag), Some(name)) if !tag.contains(['/', ':', '@']) => Ok(Reference {
+            tag: Some(tag.clone()),
+            digest: None,
+            ..name.clone()
+        }),
+        (Some(tag), None) if !tag.contains(['/', ':', '@']) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Image is only tagged as '{tag}', pass a name for it"),
+        )),
+        (Some(full), _) => full.parse(),
+        (None, Some(name)) => Ok(name.clone()),
+        (None, None) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Image has no name, pass a name for it",
+        )),
+    }
+}
+
+impl Storage {
+    /// Import images from a tarball, see `import_dir`
+    pub fn import_tar(
+        &self,
+        archive: impl Read,
+        name: Option<&Reference>,
+    ) -> Result<Vec<Reference>, std::io::Error> {
+        // Docker archives refer to files by path and entries can come in
+        // any order, so everything is unpacked first. Inside the store, so
+        // that blobs can be renamed into place
+        let staging =
+            self.cache_dir
+                .join(format!("import{}{}", Self::TMP_SUFFIX, std::process::id()));
+        util::remove_tree(&staging)?;
+
+        let result = tar::Archive::new(archive)
+            .unpack(&staging)
+            .and_then(|()| self.import(&staging, true, name));
+
+        if let Err(err) = util::remove_tree(&staging) {
+            log::warn!("Failed to cleanup {staging:?}: {err}");
+        }
+
+        result
+    }
+
+    /// Import images from a docker archive or an OCI image layout unpacked
+    /// at `dir`, `name` is used for images that the archive doesn't name
+    /// Returns the references to the imported images
+    pub fn import_dir(
+        &self,
+        dir: &Path,
+        name: Option<&Reference>,
+    ) -> Result<Vec<Reference>, std::io::Error> {
+        self.import(dir, false, name)
+    }
+
+    /// Files are moved out of `owned` directories rather than copied
+    fn import(
+        &self,
+        dir: &Path,
+        owned: bool,
+        name: Option<&Reference>,
+    ) -> Result<Vec<Reference>, std::io::Error> {
+        let _gc = self.lock_gc(false)?;
+
+        // Recent versions of docker write both, the OCI layout preserves
+        // the original manifest digests
+        if dir.join(OCI_LAYOUT).exists() {
+            self.import_oci(dir, owned, name)
+        } else if dir.join(DOCKER_MANIFEST).exists() {
+            self.import_docker(dir, owned, name)
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{dir:?} is neither an OCI image layout nor a docker archive"),
+            ))
+        }
+    }
+
+    fn import_oci(
+        &self,
+        dir: &Path,
+        owned: bool,
+        name: Option<&Reference>,
+    ) -> Result<Vec<Reference>, std::io::Error> {
+        let index: LayoutIndex = read_json(&dir.join(OCI_INDEX))?;
+        let mut imported = Vec::new();
+
+        for descriptor in &index.manifests {
+            let reference = layout_name(&descriptor.annotations, name)?;
+            let (digest, raw_manifest) = Self::layout_manifest(dir, descriptor)?;
+
+            log::info!("Importing {reference} ({digest})");
+
+            let manifest = self.import_image(owned, &digest, &raw_manifest, |digest| {
+                archive_path(dir, blob_name(digest)?)
+            })?;
+
+            self.record(&reference, &digest, &manifest.config.digest)?;
+            imported.push(reference);
+        }
+
+        Ok(imported)
+    }
+
+    /// Read the manifest that a descriptor points to, picking the one for
+    /// our platform from multi-platform images
+    fn layout_manifest(
+        dir: &Path,
+        descriptor: &LayoutDescriptor,
+    ) -> Result<(String, String), std::io::Error> {
+        let raw = std::fs::read_to_string(archive_path(dir, blob_name(&descriptor.digest)?)?)?;
+        digest::verify_bytes(raw.as_bytes(), &descriptor.digest)?;
+
+        match descriptor.media_type.as_str() {
+            RegistryClient::IMAGE_INDEX_V1 | RegistryClient::MANIFEST_LIST_V2 => {
+                let index: LayoutIndex = util::serde_deserialize_or_err(&raw)?;
+
+                let descriptor = index
+                    .manifests
+                    .iter()
+                    .find(|manifest| {
+                        manifest.platform.as_ref().is_none_or(|platform| {
+                            util::is_compatible_arch(&platform.architecture)
+                                && platform.os == "linux"
+                        })
+                    })
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("{} has no manifest for our platform", descriptor.digest),
+                        )
+                    })?;
+
+                Self::layout_manifest(dir, descriptor)
+            }
+            _ => Ok((descriptor.digest.clone(), raw)),
+        }
+    }
+
+    fn import_docker(
+        &self,
+        dir: &Path,
+        owned: bool,
+        name: Option<&Reference>,
+    ) -> Result<Vec<Reference>, std::io::Error> {
+        let entries: Vec<ArchiveManifest> = read_json(&dir.join(DOCKER_MANIFEST))?;
+        let mut images = Vec::new();
+
+        // Images can share layers, possibly through symlinks, so every path
+        // is resolved and hashed before any of them is moved into the store
+        let mut blobs = HashMap::new();
+        let mut hashed: HashMap<PathBuf, (String, String, u64)> = HashMap::new();
+
+        for entry in entries {
+            let config_path = archive_path(dir, &entry.config)?;
+            let config = std::fs::read(&config_path)?;
+            let config_digest = digest::sha256(&config);
+
+            blobs.insert(config_digest.clone(), config_path);
+            let mut layers = Vec::new();
+
+            // Docker archives don't have a manifest with digests, so we make
+            // one up from the layer tarballs, which docker doesn't compress
+            for layer in &entry.layers {
+                let path = archive_path(dir, layer)?;
+
+                let (media_type, digest, size) = match hashed.get(&path) {
+                    Some(layer) => layer.clone(),
+                    None => {
+                        let mut magic = Vec::new();
+                        File::open(&path)?.take(4).read_to_end(&mut magic)?;
+
+                        let media_type = match Compression::sniff(&magic) {
+                            Compression::None => OCI_LAYER.to_string(),
+                            Compression::Gzip => format!("{OCI_LAYER}+gzip"),
+                            Compression::Zstd => format!("{OCI_LAYER}+zstd"),
+                        };
+
+                        let (digest, size) = digest::sha256_reader(File::open(&path)?)?;
+                        let layer = (media_type, digest, size);
+
+                        hashed.insert(path.clone(), layer.clone());
+                        layer
+                    }
+                };
+
+                layers.push(ManifestConfig {
+                    media_type,
+                    size,
+                    digest: digest.clone(),
+                });
+                blobs.insert(digest, path);
+            }
+
+            let manifest = Manifest {
+                schema_version: 2,
+                media_type: RegistryClient::MANIFEST_V1.to_string(),
+                config: ManifestConfig {
+                    media_type: OCI_CONFIG.to_string(),
+                    size: config.len() as u64,
+                    digest: config_digest.clone(),
+                },
+                layers,
+            };
+
+            let raw_manifest = util::serde_result_to_ureq(serde_json::to_string(&manifest))?;
+            let manifest_digest = digest::sha256(raw_manifest.as_bytes());
+
+            let references = match (entry.repo_tags.unwrap_or_default(), name) {
+                (tags, _) if !tags.is_empty() => tags
+                    .iter()
+                    .map(|tag| tag.parse())
+                    .collect::<Result<Vec<Reference>, _>>()?,
+                (_, Some(name)) => vec![name.clone()],
+                (_, None) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Image {config_digest} has no name, pass a name for it"),
+                    ))
+                }
+            };
+
+            images.push((references, manifest_digest, raw_manifest, config_digest));
+        }
+
+        let mut imported = Vec::new();
+
+        for (references, manifest_digest, raw_manifest, config_digest) in images {
+            for reference in &references {
+                log::info!("Importing {reference} ({manifest_digest})");
+            }
+
+            self.import_image(owned, &manifest_digest, &raw_manifest, |digest| {
+                blobs.get(digest).cloned().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{digest} is missing from the archive"),
+                    )
+                })
+            })?;
+
+            for reference in references {
+                self.record(&reference, &manifest_digest, &config_digest)?;
+                imported.push(reference);
+            }
+        }
+
+        Ok(imported)
+    }
+
+    /// Store the manifest, config and layers of an image, `blob` maps a
+    /// digest to it's file in the archive
+    fn import_image(
+        &self,
+        owned: bool,
+        manifest_digest: &str,
+        raw_manifest: &str,
+        blob: impl Fn(&str) -> Result<PathBuf, std::io::Error>,
+    ) -> Result<Manifest, std::io::Error> {
+        let manifest: Manifest = util::serde_deserialize_or_err(raw_manifest)?;
+        self.write_blob(manifest_digest, raw_manifest.as_bytes())?;
+
+        let config = std::fs::read(blob(&manifest.config.digest)?)?;
+        self.write_blob(&manifest.config.digest, &config)?;
+
+        // Fail early rather than when running it
+        let _: ImageConfig = util::serde_result_to_ureq(serde_json::from_slice(&config))?;
+
+        for layer in Self::distributable_layers(&manifest) {
+            self.import_layer(&blob(&layer.digest)?, owned, layer)?;
+        }
+
+        Ok(manifest)
+    }
+
+    fn import_layer(
+        &self,
+        path: &Path,
+        owned: bool,
+        descriptor: &ManifestConfig,
+    ) -> Result<(), std::io::Error> {
+        let _lock = self.lock_digest(&descriptor.digest)?;
+
+        if self.layer_path(&descriptor.digest)?.try_exists()? {
+            log::debug!("Layer {} already exists", descriptor.digest);
+            return Ok(());
+        }
+
+        log::info!("Extracting layer {}", descriptor.digest);
+
+        // Staged like a download, as the blob is moved into the store
+        let downloads_dir = self.downloads_dir();
+        std::fs::create_dir_all(&downloads_dir)?;
+
+        let staged = downloads_dir.join(format!("{}.partial", descriptor.digest));
+
+        if owned {
+            std::fs::rename(path, &staged)?;
+        } else {
+            std::fs::copy(path, &staged)?;
+        }
+
+        self.install_layer(&staged, descriptor)?;
+
+        Ok(())
+    }
+
+    /// Write images as a tarball that's both a docker archive and an OCI
+    /// image layout, like recent versions of `docker save`
+    pub fn export(&self, references: &[Reference], out: impl Write) -> Result<(), std::io::Error> {
+        let _gc = self.lock_gc(false)?;
+
+        // Resolved upfront, as the builder terminates the archive when
+        // dropped, even if we fail halfway
+        let images = references
+            .iter()
+            .map(|reference| {
+                let record = self.lookup(reference)?.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("No such image: '{reference}'"),
+                    )
+                })?;
+                let (manifest, _) = self.load(&record)?;
+
+                Ok((reference, record, manifest))
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+        let mut builder = tar::Builder::new(out);
+        let mut written = HashSet::new();
+
+        let mut index = LayoutIndex {
+            schema_version: 2,
+            media_type: Some(RegistryClient::IMAGE_INDEX_V1.to_string()),
+            manifests: Vec::new(),
+        };
+        let mut archive = Vec::new();
+
+        for (reference, record, manifest) in &images {
+            let layers = Self::distributable_layers(manifest);
+
+            if layers.len() != manifest.layers.len() {
+                log::warn!("Non-distributable layers of {reference} are not included");
+            }
+
+            let blobs = [&record.manifest, &record.config]
+                .into_iter()
+                .chain(layers.iter().map(|layer| &layer.digest));
+
+            for digest in blobs {
+                if !written.insert(digest.clone()) {
+                    continue;
+                }
+
+                let path = self.blob_path(digest)?;
+                let mut file = File::open(&path).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Can't read {digest} of {reference}, pull it again: {err}"),
+                    )
+                })?;
+
+                builder.append_file(blob_name(digest)?, &mut file)?;
+            }
+
+            let mut annotations =
+                BTreeMap::from([(IMAGE_NAME_ANNOTATION.to_string(), reference.to_string())]);
+
+            if let Some(tag) = &reference.tag {
+                annotations.insert(REF_NAME_ANNOTATION.to_string(), tag.clone());
+            }
+
+            index.manifests.push(LayoutDescriptor {
+                media_type: if manifest.media_type.is_empty() {
+                    RegistryClient::MANIFEST_V1.to_string()
+                } else {
+                    manifest.media_type.clone()
+                },
+                digest: record.manifest.clone(),
+                size: std::fs::metadata(self.blob_path(&record.manifest)?)?.len(),
+                annotations,
+                platform: None,
+            });
+
+            archive.push(ArchiveManifest {
+                config: blob_name(&record.config)?.to_string_lossy().into_owned(),
+                // registry/repository:tag, without the digest
+                repo_tags: reference.tag.as_ref().map(|tag| {
+                    vec![format!(
+                        "{}/{}:{tag}",
+                        reference.registry, reference.repository
+                    )]
+                }),
+                layers: layers
+                    .iter()
+                    .map(|layer| Ok(blob_name(&layer.digest)?.to_string_lossy().into_owned()))
+                    .collect::<Result<_, std::io::Error>>()?,
+            });
+        }
+
+        append_json(
+            &mut builder,
+            OCI_LAYOUT,
+            &OciLayout {
+                image_layout_version: String::from("1.0.0"),
+            },
+        )?;
+        append_json(&mut builder, OCI_INDEX, &index)?;
+        append_json(&mut builder, DOCKER_MANIFEST, &archive)?;
+
+        builder.into_inner()?.flush()
+    }
+}
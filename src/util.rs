@@ -1,8 +1,11 @@
-use nix::{dir::Dir, fcntl::OFlag, sys::stat::Mode};
+use nix::{dir::Dir, fcntl::OFlag, sys::signal::Signal, sys::stat::Mode};
 use std::env::consts::ARCH;
 use std::io::Read;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::process::{Child, Output};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Wait for a process to exit and store it's output without
 /// moving the object
@@ -112,6 +115,34 @@ pub fn set_env(env: &[String]) {
     }
 }
 
+/// `PATH` for containers that don't set one
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Remove a directory tree extracted from a tarball. Directories without
+/// write permission must be fixed up before their entries can be removed
+/// Entries that disappear meanwhile are fine, such as the sockets of a
+/// container's processes that are still exiting
+pub fn remove_tree(path: &Path) -> Result<(), std::io::Error> {
+    let remove = || {
+        if std::fs::symlink_metadata(path)?.is_dir() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))?;
+
+            for entry in std::fs::read_dir(path)? {
+                remove_tree(&entry?.path())?;
+            }
+
+            std::fs::remove_dir(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    };
+
+    match remove() {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
 pub fn is_compatible_arch(image_arch: &str) -> bool {
     // Normalize the architecture names to match up with
     // https://doc.rust-lang.org/std/env/consts/constant.ARCH.html
@@ -132,11 +163,6 @@ pub fn is_compatible_arch(image_arch: &str) -> bool {
     normalized == ARCH
 }
 
-pub fn get_base_path() -> &'static str {
-    static BASE_DIR: &str = "/tmp/dabba";
-    BASE_DIR
-}
-
 /// Parse the JSON object or return an std::io::Error instance
 pub fn serde_deserialize_or_err<T: serde::de::DeserializeOwned>(json: &str) -> std::io::Result<T> {
     match serde_json::from_str(json) {
@@ -157,3 +183,219 @@ pub fn serde_result_to_ureq<T>(result: serde_json::Result<T>) -> std::io::Result
         )),
     }
 }
+
+/// Flatten a ureq::Error into an std::io::Error, keeping the HTTP status
+/// and URL around for context
+pub fn ureq_to_io(err: ureq::Error) -> std::io::Error {
+    match err {
+        ureq::Error::Status(code, response) => std::io::Error::new(
+            match code {
+                401 | 403 => std::io::ErrorKind::PermissionDenied,
+                404 => std::io::ErrorKind::NotFound,
+                _ => std::io::ErrorKind::Other,
+            },
+            format!(
+                "{} returned status {code} ({})",
+                response.get_url(),
+                response.status_text()
+            ),
+        ),
+        ureq::Error::Transport(transport) => std::io::Error::other(transport.to_string()),
+    }
+}
+
+/// Days since the unix epoch for a proleptic Gregorian date
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+/// Parse an RFC 3339 timestamp as used by registries and image configs,
+/// `2023-10-05T12:00:00.123456789Z` or `2023-10-05T12:00:00+05:30`
+pub fn parse_rfc3339(timestamp: &str) -> Option<SystemTime> {
+    let (date, time) = timestamp.split_once(['T', 't', ' '])?;
+
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    // Split off the timezone offset, fractional seconds are ignored
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let idx = time.rfind(['+', '-'])?;
+        let (hours, minutes) = time[idx + 1..].split_once(':')?;
+        let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+
+        (
+            &time[..idx],
+            if time[idx..].starts_with('-') {
+                -offset
+            } else {
+                offset
+            },
+        )
+    };
+
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// Format a size in bytes for humans, 1.5MB
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{size:.1}{}", UNITS[unit])
+    }
+}
+
+/// Format how long ago something happened, `3 days ago`
+pub fn human_age(time: SystemTime) -> String {
+    const UNITS: [(u64, &str); 6] = [
+        (365 * 86400, "year"),
+        (30 * 86400, "month"),
+        (7 * 86400, "week"),
+        (86400, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+    ];
+
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    for (unit, name) in UNITS {
+        if secs >= unit {
+            let count = secs / unit;
+            return format!("{count} {name}{} ago", if count == 1 { "" } else { "s" });
+        }
+    }
+
+    String::from("Less than a minute ago")
+}
+
+/// Parse a size with an optional decimal unit, `500MB`, `10G`, `1024`
+pub fn parse_size(size: &str) -> Option<u64> {
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        _ => return None,
+    };
+
+    Some((number.parse::<f64>().ok()? * multiplier as f64) as u64)
+}
+
+/// Parse a duration with a unit, `30m`, `12h`, `7d`, `2w`
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let (idx, _) = duration.char_indices().last()?;
+    let (number, unit) = duration.split_at(idx);
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(
+        number.parse::<u64>().ok()?.checked_mul(multiplier)?,
+    ))
+}
+
+/// Parse a signal by name or number, `SIGTERM`, `term`, `15`
+pub fn parse_signal(signal: &str) -> Option<Signal> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+
+    let name = signal.to_ascii_uppercase();
+
+    if name.starts_with("SIG") {
+        name.parse().ok()
+    } else {
+        format!("SIG{name}").parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(timestamp: &str) -> Option<u64> {
+        parse_rfc3339(timestamp).map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn rfc3339_utc() {
+        assert_eq!(secs("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(secs("2024-02-29T12:34:56Z"), Some(1709210096));
+        assert_eq!(secs("2024-02-29t12:34:56z"), Some(1709210096));
+        assert_eq!(secs("2024-02-29 12:34:56Z"), Some(1709210096));
+    }
+
+    #[test]
+    fn rfc3339_fractional_seconds() {
+        assert_eq!(secs("2024-02-29T12:34:56.123456789Z"), Some(1709210096));
+    }
+
+    #[test]
+    fn rfc3339_offsets() {
+        assert_eq!(secs("2024-02-29T14:34:56+02:00"), Some(1709210096));
+        assert_eq!(secs("2024-02-29T07:04:56.5-05:30"), Some(1709210096));
+    }
+
+    #[test]
+    fn rfc3339_invalid() {
+        assert_eq!(secs(""), None);
+        assert_eq!(secs("2024-02-29"), None);
+        assert_eq!(secs("2024-02-29T12:34Z"), None);
+        assert_eq!(secs("2024-02-29T12:34:56"), None);
+        assert_eq!(secs("1969-12-31T23:59:59Z"), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2w"), Some(Duration::from_secs(1209600)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("30"), None);
+        assert_eq!(parse_duration("30é"), None);
+        assert_eq!(parse_duration("é"), None);
+        assert_eq!(parse_duration("18446744073709551615w"), None);
+    }
+}
@@ -0,0 +1,443 @@
+//! Pushing against an in-process stand-in for a registry, which records
+//! every request it receives
+
+use dabba::digest;
+use dabba::reference::Reference;
+use dabba::registry::{BlobPush, ManifestConfig, RegistryClient};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+const REPOSITORY: &str = "test/repo";
+const OTHER_REPOSITORY: &str = "test/other";
+const LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    /// Lowercase names
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+struct Registry {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Registry {
+    /// `handler` answers everything except the `/v2/` probe, which lets
+    /// anonymous clients through
+    fn start(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (recorded, handler) = (recorded.clone(), handler.clone());
+                std::thread::spawn(move || serve(stream.unwrap(), &recorded, &*handler));
+            }
+        });
+
+        Self { port, requests }
+    }
+
+    fn reference(&self, repository: &str) -> Reference {
+        format!("127.0.0.1:{}/{repository}:latest", self.port)
+            .parse()
+            .unwrap()
+    }
+
+    fn requests(&self) -> Vec<Request> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.path != "/v2/")
+            .cloned()
+            .collect()
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => {
+                decoded.push(u8::from_str_radix(&value[idx + 1..idx + 3], 16).unwrap());
+                idx += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                idx += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).unwrap()
+}
+
+/// Answer requests on a keep-alive connection until the client closes it
+fn serve(stream: TcpStream, recorded: &Mutex<Vec<Request>>, handler: &Handler) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap().to_string();
+        let target = parts.next().unwrap();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => {
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+                }
+                None => break,
+            }
+        }
+
+        let length = headers
+            .get("content-length")
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let request = Request {
+            method,
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (percent_decode(key), percent_decode(value))
+                })
+                .collect(),
+            headers,
+            body,
+        };
+        recorded.lock().unwrap().push(request.clone());
+
+        let response = if request.path == "/v2/" {
+            Response::new(200)
+        } else {
+            handler(&request)
+        };
+
+        let mut head = format!(
+            "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\n",
+            response.status,
+            response.body.len()
+        );
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes()).unwrap();
+        if request.method != "HEAD" {
+            writer.write_all(&response.body).unwrap();
+        }
+    }
+}
+
+fn descriptor(blob: &[u8]) -> ManifestConfig {
+    ManifestConfig {
+        media_type: LAYER.to_string(),
+        size: blob.len() as u64,
+        digest: digest::sha256(blob),
+    }
+}
+
+fn blob_path(repository: &str, digest: &str) -> String {
+    format!("/v2/{repository}/blobs/{digest}")
+}
+
+fn uploads_path(repository: &str) -> String {
+    format!("/v2/{repository}/blobs/uploads/")
+}
+
+/// Accepts uploads of any blob, with a new session location per request
+fn upload_handler(request: &Request) -> Response {
+    match request.method.as_str() {
+        "HEAD" => Response::new(404),
+        "POST" => Response::new(202).header("Location", "/v2/test/repo/blobs/uploads/session0"),
+        "PATCH" => Response::new(202).header("Location", "/v2/test/repo/blobs/uploads/session1"),
+        "PUT" => Response::new(201),
+        _ => Response::new(405),
+    }
+}
+
+#[test]
+fn has_blob() {
+    let existing = digest::sha256(b"existing");
+    let path = blob_path(REPOSITORY, &existing);
+
+    let registry = Registry::start(move |request| {
+        if request.method == "HEAD" && request.path == path {
+            Response::new(200)
+        } else {
+            Response::new(404)
+        }
+    });
+    let client = RegistryClient::new(&registry.reference(REPOSITORY));
+
+    assert!(client.has_blob(&existing).unwrap());
+    assert!(!client.has_blob(&digest::sha256(b"missing")).unwrap());
+}
+
+#[test]
+fn monolithic_upload() {
+    let registry = Registry::start(upload_handler);
+    let client = RegistryClient::for_push(&registry.reference(REPOSITORY), None);
+
+    let blob = b"small layer";
+    let descriptor = descriptor(blob);
+
+    assert_eq!(
+        client.push_blob(&descriptor, &blob[..]).unwrap(),
+        BlobPush::Uploaded
+    );
+
+    let requests = registry.requests();
+    let methods: Vec<_> = requests.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, ["HEAD", "POST", "PUT"]);
+
+    assert_eq!(requests[0].path, blob_path(REPOSITORY, &descriptor.digest));
+    assert_eq!(requests[1].path, uploads_path(REPOSITORY));
+    assert!(requests[1].query.is_empty());
+
+    let put = &requests[2];
+    assert_eq!(put.path, "/v2/test/repo/blobs/uploads/session0");
+    assert_eq!(put.query("digest"), Some(descriptor.digest.as_str()));
+    assert_eq!(put.header("content-type"), Some("application/octet-stream"));
+    assert_eq!(put.body, blob);
+}
+
+#[test]
+fn chunked_upload() {
+    let registry = Registry::start(upload_handler);
+    let client = RegistryClient::for_push(&registry.reference(REPOSITORY), None);
+
+    let size = RegistryClient::UPLOAD_CHUNK_SIZE as usize;
+    let blob: Vec<u8> = (0..size).map(|idx| idx as u8).collect();
+    let descriptor = descriptor(&blob);
+
+    assert_eq!(
+        client.push_blob(&descriptor, blob.as_slice()).unwrap(),
+        BlobPush::Uploaded
+    );
+
+    let requests = registry.requests();
+    let methods: Vec<_> = requests.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, ["HEAD", "POST", "PATCH", "PUT"]);
+
+    let patch = &requests[2];
+    assert_eq!(patch.path, "/v2/test/repo/blobs/uploads/session0");
+    assert_eq!(
+        patch.header("content-range"),
+        Some(format!("0-{}", size - 1).as_str())
+    );
+    assert!(patch.body == blob);
+
+    // The blob ends exactly on a chunk boundary, so nothing is left for
+    // the request closing the session
+    let put = &requests[3];
+    assert_eq!(put.path, "/v2/test/repo/blobs/uploads/session1");
+    assert_eq!(put.query("digest"), Some(descriptor.digest.as_str()));
+    assert!(put.body.is_empty());
+}
+
+#[test]
+fn mounted_blob() {
+    let registry = Registry::start(|request| match request.method.as_str() {
+        "HEAD" => Response::new(404),
+        "POST" => Response::new(201),
+        _ => Response::new(405),
+    });
+    let client = RegistryClient::for_push(
+        &registry.reference(REPOSITORY),
+        Some(&registry.reference(OTHER_REPOSITORY)),
+    );
+
+    let descriptor = descriptor(b"shared layer");
+
+    assert_eq!(
+        client.push_blob(&descriptor, &b"shared layer"[..]).unwrap(),
+        BlobPush::Mounted
+    );
+
+    let requests = registry.requests();
+    assert_eq!(requests.len(), 2);
+
+    let post = &requests[1];
+    assert_eq!(post.path, uploads_path(REPOSITORY));
+    assert_eq!(post.query("mount"), Some(descriptor.digest.as_str()));
+    assert_eq!(post.query("from"), Some(OTHER_REPOSITORY));
+}
+
+#[test]
+fn mount_falls_back_to_upload() {
+    let registry = Registry::start(upload_handler);
+    let client = RegistryClient::for_push(
+        &registry.reference(REPOSITORY),
+        Some(&registry.reference(OTHER_REPOSITORY)),
+    );
+
+    let blob = b"unmountable layer";
+    let descriptor = descriptor(blob);
+
+    assert_eq!(
+        client.push_blob(&descriptor, &blob[..]).unwrap(),
+        BlobPush::Uploaded
+    );
+
+    let requests = registry.requests();
+    let methods: Vec<_> = requests.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, ["HEAD", "POST", "PUT"]);
+
+    assert_eq!(requests[1].query("from"), Some(OTHER_REPOSITORY));
+    assert_eq!(requests[2].body, blob);
+}
+
+#[test]
+fn manifest_content_type() {
+    let manifest = r#"{"schemaVersion":2}"#;
+    let digest = digest::sha256(manifest.as_bytes());
+
+    let returned = digest.clone();
+    let registry = Registry::start(move |request| match request.method.as_str() {
+        "PUT" => Response::new(201).header("Docker-Content-Digest", returned.clone()),
+        _ => Response::new(405),
+    });
+    let client = RegistryClient::for_push(&registry.reference(REPOSITORY), None);
+
+    assert_eq!(
+        client
+            .put_manifest(manifest, RegistryClient::MANIFEST_V1)
+            .unwrap(),
+        digest
+    );
+
+    let requests = registry.requests();
+    assert_eq!(requests.len(), 1);
+
+    let put = &requests[0];
+    assert_eq!(put.path, "/v2/test/repo/manifests/latest");
+    assert_eq!(
+        put.header("content-type"),
+        Some(RegistryClient::MANIFEST_V1)
+    );
+    assert_eq!(put.body, manifest.as_bytes());
+}
+
+#[test]
+fn token_refreshed_on_401() {
+    let digest = digest::sha256(b"layer");
+    let path = blob_path(REPOSITORY, &digest);
+    let port = Arc::new(Mutex::new(0));
+
+    // The base endpoint is anonymous, but the repository is not
+    let (realm_port, blob) = (port.clone(), path.clone());
+    let registry = Registry::start(move |request| {
+        if request.path == "/token" {
+            return Response::new(200).body(r#"{"token":"pushable"}"#);
+        }
+
+        if request.header("authorization") != Some("Bearer pushable") {
+            let realm = format!("http://127.0.0.1:{}/token", realm_port.lock().unwrap());
+
+            return Response::new(401).header(
+                "WWW-Authenticate",
+                format!(r#"Bearer realm="{realm}",service="stand-in""#),
+            );
+        }
+
+        if request.method == "HEAD" && request.path == blob {
+            Response::new(200)
+        } else {
+            Response::new(404)
+        }
+    });
+    *port.lock().unwrap() = registry.port;
+
+    let client = RegistryClient::for_push(&registry.reference(REPOSITORY), None);
+
+    assert!(client.has_blob(&digest).unwrap());
+
+    let requests = registry.requests();
+    let paths: Vec<_> = requests.iter().map(|r| r.path.as_str()).collect();
+    assert_eq!(paths, [path.as_str(), "/token", path.as_str()]);
+
+    let token = &requests[1];
+    assert_eq!(token.query("service"), Some("stand-in"));
+    assert_eq!(
+        token.query("scope"),
+        Some(format!("repository:{REPOSITORY}:pull,push").as_str())
+    );
+    assert_eq!(requests[2].header("authorization"), Some("Bearer pushable"));
+}